@@ -1,4 +1,4 @@
-use semtech_udp::client_runtime::UdpRuntime;
+use semtech_udp::client_runtime::{BufferConfig, Config, Storage, UdpRuntime};
 use semtech_udp::Up::PushData;
 use std::net::SocketAddr;
 use std::str::FromStr;
@@ -13,10 +13,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let outbound = SocketAddr::from(([0, 0, 0, 0], cli.port));
     let host = SocketAddr::from_str(cli.host.as_str())?;
     println!("Connecting to server {} from port {}", cli.host, cli.port);
-    let udp_runtime = UdpRuntime::new(mac_address.clone(), outbound, host).await?;
+    let config = Config {
+        stat_interval: Some(Duration::from_secs(30)),
+        buffer: Some(BufferConfig {
+            capacity: 256,
+            max_attempts: 8,
+            storage: Storage::Memory,
+        }),
+        ..Default::default()
+    };
+    let udp_runtime = UdpRuntime::new_with_config(mac_address, outbound, host, config).await?;
 
     let (mut receiver, sender) = (udp_runtime.subscribe(), udp_runtime.publish_to());
 
+    let mut connection_events = udp_runtime.health().subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = connection_events.recv().await {
+            println!("Connection {:?}: {:?}", event.state, event.stats);
+        }
+    });
+
     tokio::spawn(async move {
         udp_runtime.run().await.unwrap();
     });
@@ -0,0 +1,58 @@
+use semtech_udp::proxy::{Config, Event, Proxy};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Opt::from_args();
+    let listen = SocketAddr::from(([0, 0, 0, 0], cli.port));
+    let mut servers = Vec::new();
+    for server in &cli.server {
+        servers.push(SocketAddr::from_str(server)?);
+    }
+
+    println!("Starting proxy: {} -> {:?}", listen, servers);
+    let proxy = Proxy::new(Config {
+        listen,
+        servers,
+        ack_timeout: Duration::from_secs(30),
+    })
+    .await?;
+
+    let mut events = proxy.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            match event {
+                Event::GatewayConnected(mac, addr) => {
+                    println!("New packet forwarder client: {}, {}", mac, addr)
+                }
+                Event::TokenRewritten(mac, from, to) => {
+                    println!("Downlink token {} rewritten to {} for {}", from, to, mac)
+                }
+                Event::UnableToParseUdpFrame(frame) => {
+                    println!(
+                        "Semtech UDP Parsing Error from {}: {}",
+                        frame.src, frame.error
+                    );
+                    println!("UDP data: {:?}", frame.frame);
+                }
+            }
+        }
+    });
+
+    proxy.run().await?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "lora-proxy", about = "Transparent Semtech GWMP over UDP Proxy")]
+pub struct Opt {
+    /// port the gateways connect to
+    #[structopt(short, long, default_value = "1681")]
+    pub port: u16,
+    /// servers to relay to (eg: 127.0.0.1:1680)
+    #[structopt(long, default_value = "127.0.0.1:1680")]
+    pub server: Vec<String>,
+}
@@ -1,7 +1,7 @@
 use semtech_udp::{
     pull_resp,
     server_runtime::{Event, UdpRuntime},
-    CodingRate, DataRate, Modulation, StringOrNum,
+    CodingRate, DataRate, Modulation,
 };
 use std::net::SocketAddr;
 use std::time::Duration;
@@ -17,9 +17,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     loop {
         println!("Waiting for event");
         match udp_runtime.recv().await {
-            Event::UnableToParseUdpFrame(buf) => {
-                println!("Semtech UDP Parsing Error");
-                println!("UDP data: {:?}", buf);
+            Event::UnableToParseUdpFrame(frame) => {
+                println!(
+                    "Semtech UDP Parsing Error from {}: {}",
+                    frame.src, frame.error
+                );
+                println!("UDP data: {:?}", frame.frame);
             }
             Event::NewClient((mac, addr)) => {
                 println!("New packet forwarder client: {}, {}", mac, addr);
@@ -32,7 +35,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 let data = vec![1, 2, 3, 4];
                 let size = data.len() as u64;
-                let tmst = StringOrNum::N(rxpk.get_timestamp() + 1_000_000);
+                let tmst = Some((rxpk.get_timestamp() + Duration::from_secs(1)).into());
 
                 let txpk = pull_resp::TxPk {
                     imme: false,
@@ -50,6 +53,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     fdev: None,
                     prea: None,
                     ncrc: None,
+                    nhdr: None,
+                    extra: serde_json::Map::new(),
                 };
 
                 let prepared_send = udp_runtime.prepare_downlink(txpk, gateway_mac);
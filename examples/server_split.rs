@@ -32,7 +32,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 first_shot = false;
                 let data = vec![0; cli.length];
                 let size = data.len() as u64;
-                let tmst = StringOrNum::S("immedate".into());
+                let tmst = Some(StringOrNum::S("immedate".into()));
 
                 let txpk = pull_resp::TxPk {
                     imme: true,
@@ -50,6 +50,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     fdev: None,
                     prea: None,
                     ncrc: None,
+                    nhdr: None,
+                    extra: serde_json::Map::new(),
                 };
 
                 println!("Sending: {}", txpk);
@@ -72,9 +74,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Ready for clients");
     loop {
         match client_rx.recv().await {
-            Event::UnableToParseUdpFrame(buf) => {
-                println!("Semtech UDP Parsing Error");
-                println!("UDP data: {:?}", buf);
+            Event::UnableToParseUdpFrame(frame) => {
+                println!(
+                    "Semtech UDP Parsing Error from {}: {}",
+                    frame.src, frame.error
+                );
+                println!("UDP data: {:?}", frame.frame);
             }
             Event::NewClient((mac, addr)) => {
                 println!("New packet forwarder client: {}, {}", mac, addr);
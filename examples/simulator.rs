@@ -0,0 +1,74 @@
+use semtech_udp::client_runtime::GatewayProfile;
+use semtech_udp::simulator::{Config, Event, RxPkFormat, Simulator};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::time::sleep;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Opt::from_args();
+    let config = Config {
+        host: SocketAddr::from_str(&cli.host)?,
+        gateways: cli.gateways,
+        uplink_interval: Duration::from_millis(cli.uplink_interval),
+        stat_interval: Duration::from_secs(cli.stat_interval),
+        rxpk_format: if cli.v2 {
+            RxPkFormat::V2
+        } else {
+            RxPkFormat::V1
+        },
+        jit: if cli.jit {
+            Some(GatewayProfile::default())
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+
+    println!(
+        "Simulating {} gateways against {}",
+        config.gateways, config.host
+    );
+    let simulator = Simulator::new(config).await?;
+
+    let mut events = simulator.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            match event {
+                Event::DownlinkReceived(mac, packet) => {
+                    println!("Downlink for {}: {}", mac, packet.data.txpk)
+                }
+            }
+        }
+    });
+
+    loop {
+        sleep(Duration::from_secs(5)).await;
+        println!("{:?}", simulator.report());
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "Semtech GWMP over UDP Packet Forwarder Simulator")]
+pub struct Opt {
+    /// server to connect the virtual gateways to
+    #[structopt(long, default_value = "127.0.0.1:1680")]
+    pub host: String,
+    /// number of virtual gateways
+    #[structopt(long, default_value = "10")]
+    pub gateways: usize,
+    /// mean milliseconds between uplinks of a single gateway
+    #[structopt(long, default_value = "10000")]
+    pub uplink_interval: u64,
+    /// seconds between stat reports of a single gateway
+    #[structopt(long, default_value = "30")]
+    pub stat_interval: u64,
+    /// send rxpk in the multi-antenna V2 format
+    #[structopt(long)]
+    pub v2: bool,
+    /// check downlinks against an emulated lora_pkt_fwd JIT queue
+    #[structopt(long)]
+    pub jit: bool,
+}
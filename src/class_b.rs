@@ -0,0 +1,248 @@
+/*
+   LoRaWAN Class B beacons, which gateways with a GPS lock emit every 128
+   seconds of GPS time so that devices can open ping slots in sync with
+   the network. The beacon frame has no PHY header nor CRC, and carries:
+
+   Bytes | RFU  | Time | CRC | GwSpecific | RFU  | CRC
+   :----:|:----:|:----:|:---:|:----------:|:----:|:---:
+         | 1..5 | 4    | 2   | 7          | 0..3 | 2
+
+   Time is the GPS time of the start of the beacon period in seconds, and
+   GwSpecific the InfoDesc byte with the latitude and longitude of the
+   gateway antenna. The RFU sizes, frequency and DR depend on the region;
+   US915 and AU915 hop over their 8 downlink channels on every beacon.
+
+   Both CRCs are CRC-16/CCITT with a zero seed, over the fields before
+   them, sent little endian like every other field.
+*/
+use crate::{
+    pull_resp::TxPk,
+    push_data::Stat,
+    region::{Error, Plan, Region},
+    time::GpsTime,
+    CodingRate, Modulation,
+};
+use std::time::Duration;
+
+pub const BEACON_PERIOD: Duration = Duration::from_secs(128);
+/// Time after the start of the beacon period before the first ping slot
+pub const BEACON_RESERVED: Duration = Duration::from_millis(2_120);
+pub const PING_SLOT_LEN: Duration = Duration::from_millis(30);
+/// Ping slots in every beacon period
+pub const PING_SLOTS: u32 = 4_096;
+
+// symbols, instead of the 8 of other downlinks
+const BEACON_PREAMBLE: u64 = 10;
+const PERIOD_MS: u64 = 128_000;
+// InfoDesc of the coordinates of the first antenna
+const INFO_DESC_ANTENNA: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beacon {
+    // GPS time of the start of the period, in seconds
+    time: u64,
+    // latitude and longitude in degrees
+    location: Option<(f64, f64)>,
+}
+
+impl Beacon {
+    /// Beacon of the period a GPS time falls in
+    pub fn at(time: GpsTime) -> Beacon {
+        Beacon {
+            time: time.as_millis() / PERIOD_MS * PERIOD_MS / 1_000,
+            location: None,
+        }
+    }
+
+    /// First beacon emitted after a GPS time
+    pub fn after(time: GpsTime) -> Beacon {
+        Beacon::at(GpsTime::from_millis(time.as_millis() + PERIOD_MS))
+    }
+
+    /// Announces the location of the gateway, which is all zeroes otherwise
+    pub fn with_location(self, latitude: f64, longitude: f64) -> Beacon {
+        Beacon {
+            location: Some((latitude, longitude)),
+            ..self
+        }
+    }
+
+    /// Announces the location the gateway reports, if it does
+    pub fn with_stat(self, stat: &Stat) -> Beacon {
+        match (stat.lati, stat.long) {
+            (Some(latitude), Some(longitude)) => self.with_location(latitude, longitude),
+            _ => self,
+        }
+    }
+
+    /// Start of the beacon period, when the beacon goes on the air
+    pub fn time(&self) -> GpsTime {
+        GpsTime::from_millis(self.time * 1_000)
+    }
+
+    /// BCNPayload of the region
+    pub fn payload(&self, region: Region) -> Result<Vec<u8>, Error> {
+        let (rfu1, rfu2) = match region {
+            Region::EU868 | Region::AS923 | Region::KR920 => (2, 0),
+            Region::US915 | Region::AU915 => (5, 3),
+            Region::IN865 => (1, 3),
+            Region::CN470 => return Err(Error::NoBeaconInRegion(region)),
+        };
+        let mut payload = vec![0; rfu1];
+        // the field wraps around, as GPS week numbers do
+        payload.extend_from_slice(&(self.time as u32).to_le_bytes());
+        payload.extend_from_slice(&crc16(&payload).to_le_bytes());
+
+        let start = payload.len();
+        payload.push(INFO_DESC_ANTENNA);
+        let (latitude, longitude) = self.location.unwrap_or_default();
+        payload.extend_from_slice(&coordinate(latitude, 90.0));
+        payload.extend_from_slice(&coordinate(longitude, 180.0));
+        payload.resize(payload.len() + rfu2, 0);
+        let crc = crc16(&payload[start..]);
+        payload.extend_from_slice(&crc.to_le_bytes());
+        Ok(payload)
+    }
+
+    /// Frequency in MHz and DR of the beacon
+    pub fn channel(&self, region: Region) -> Result<(f64, usize), Error> {
+        match region {
+            Region::EU868 => Ok((869.525, 3)),
+            Region::US915 | Region::AU915 => Ok((hop(self.time / 128), 8)),
+            Region::AS923 => Ok((923.4, 3)),
+            Region::KR920 => Ok((923.1, 3)),
+            Region::IN865 => Ok((866.55, 4)),
+            Region::CN470 => Err(Error::NoBeaconInRegion(region)),
+        }
+    }
+
+    /// Default frequency in MHz and DR of the ping slots of a device during
+    /// the beacon period; only hopping regions depend on the device
+    pub fn ping_slot_channel(&self, region: Region, dev_addr: u32) -> Result<(f64, usize), Error> {
+        match region {
+            Region::US915 | Region::AU915 => Ok((hop(dev_addr as u64 + self.time / 128), 8)),
+            _ => self.channel(region),
+        }
+    }
+
+    /// Beacon to be emitted on GPS time, at the highest power the plan
+    /// allows when `tx_power` is None
+    pub fn txpk(&self, plan: &Plan, tx_power: Option<u64>) -> Result<TxPk, Error> {
+        let (freq, dr) = self.channel(plan.region())?;
+        let datr = plan.data_rate(dr).ok_or(Error::DataRateNotAllowed {
+            dr,
+            frequency: freq,
+        })?;
+        let powe = match tx_power {
+            Some(powe) => powe,
+            None => plan
+                .max_tx_power(freq)
+                .ok_or(Error::FrequencyOutOfPlan(freq))?,
+        };
+        let data = self.payload(plan.region())?;
+        let mut txpk = TxPk {
+            imme: false,
+            tmst: None,
+            tmms: None,
+            freq,
+            rfch: 0,
+            powe,
+            modu: Modulation::LORA,
+            datr,
+            codr: CodingRate::_4_5,
+            fdev: None,
+            // devices listen to beacons without inverted polarity
+            ipol: false,
+            prea: Some(BEACON_PREAMBLE),
+            size: data.len() as u64,
+            data,
+            ncrc: Some(true),
+            nhdr: Some(true),
+            extra: serde_json::Map::new(),
+        };
+        txpk.set_gps_time(self.time());
+        Ok(txpk)
+    }
+}
+
+// one of the 8 downlink channels of US915 and AU915
+fn hop(channel: u64) -> f64 {
+    (9_233 + 6 * (channel % 8)) as f64 / 10.0
+}
+
+// signed 24 bits, scaled so that `range` degrees would be 2^23
+fn coordinate(degrees: f64, range: f64) -> [u8; 3] {
+    let full_scale = (1 << 23) as f64;
+    let scaled = (degrees / range * full_scale)
+        .round()
+        .clamp(-full_scale, full_scale - 1.0) as i32;
+    let bytes = scaled.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataRate;
+
+    #[test]
+    fn test_eu868_beacon() {
+        // example beacon of the LoRaWAN specification
+        let time = GpsTime::from_millis(0xCC02_0000 * 1_000 + 42);
+        let beacon = Beacon::at(time).with_location(
+            0x00_2001 as f64 * 90.0 / (1 << 23) as f64,
+            0x03_8100 as f64 * 180.0 / (1 << 23) as f64,
+        );
+        assert_eq!(
+            beacon.payload(Region::EU868).unwrap(),
+            vec![
+                0x00, 0x00, 0x00, 0x00, 0x02, 0xCC, 0xA2, 0x7E, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81,
+                0x03, 0xDE, 0x55
+            ]
+        );
+
+        let txpk = beacon.txpk(&Plan::new(Region::EU868), None).unwrap();
+        assert_eq!(txpk.get_gps_time(), Some(beacon.time()));
+        assert_eq!(txpk.tmst, None);
+        assert_eq!(txpk.freq, 869.525);
+        assert_eq!(txpk.datr, "SF9BW125".parse::<DataRate>().unwrap());
+        assert_eq!(txpk.powe, 27);
+        assert!(!txpk.ipol);
+        // 10 symbols of preamble and 17 bytes without header nor CRC
+        assert_eq!(txpk.time_on_air(), Duration::from_micros(152_576));
+
+        let next = Beacon::after(beacon.time());
+        assert_eq!(next.time().as_millis() - beacon.time().as_millis(), 128_000);
+        assert_eq!(Beacon::after(time), next);
+    }
+
+    #[test]
+    fn test_us915_beacon() {
+        let plan = Plan::new(Region::US915);
+        let beacon = Beacon::at(GpsTime::from_millis(128_000 * 3));
+        let txpk = beacon.txpk(&plan, Some(20)).unwrap();
+        assert_eq!(txpk.size, 23);
+        assert_eq!(txpk.freq, 925.1);
+        assert_eq!(txpk.datr, "SF12BW500".parse::<DataRate>().unwrap());
+        assert_eq!(beacon.ping_slot_channel(Region::US915, 6), Ok((923.9, 8)));
+        assert_eq!(
+            beacon.payload(Region::CN470),
+            Err(Error::NoBeaconInRegion(Region::CN470))
+        );
+    }
+}
@@ -0,0 +1,49 @@
+/*
+   Exponential backoff between retries of a failing socket. The delay
+   doubles on every consecutive failure, up to a ceiling, and starts over
+   from the floor as soon as the socket works again
+*/
+use std::time::Duration;
+
+const FLOOR: Duration = Duration::from_millis(100);
+const CEILING: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff { next: FLOOR }
+    }
+}
+
+impl Backoff {
+    /// Delay to wait before retrying after one more failure
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(CEILING);
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.next = FLOOR;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_ceiling() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<Duration> = (0..10).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays[0], FLOOR);
+        assert_eq!(delays[1], FLOOR * 2);
+        assert_eq!(delays[9], CEILING);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), FLOOR);
+    }
+}
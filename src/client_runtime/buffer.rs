@@ -0,0 +1,405 @@
+/*
+   Store-and-forward buffer for uplinks. Every PUSH_DATA carrying rxpk is
+   retained until its PUSH_ACK arrives; the ones which were not acked in time
+   are sent again, flagged as delayed, as soon as the server answers again.
+   On disk, changes to the buffer are appended to a log by a writer thread,
+   in batches, and the log is compacted once it outgrows the buffer
+*/
+use crate::push_data::{Data, RxPk};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// below this many records in the log, compacting it is not worth it
+const COMPACT_FLOOR: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum Storage {
+    Memory,
+    /// buffered uplinks are also logged to this file, one JSON object per
+    /// line, and are sent again after a restart of the runtime
+    Disk(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct BufferConfig {
+    /// maximum number of buffered uplinks, the oldest one is dropped when full
+    pub capacity: usize,
+    /// number of times an uplink is sent, the first one included, before it
+    /// is given up on if the server never acks it
+    pub max_attempts: u32,
+    pub storage: Storage,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferStats {
+    /// uplinks currently buffered, awaiting either an ack or retransmission
+    pub len: usize,
+    pub capacity: usize,
+    /// uplinks dropped because the buffer was full
+    pub dropped: u64,
+    pub retransmitted: u64,
+    /// uplinks given up on after max_attempts transmissions without ack
+    pub abandoned: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    token: u16,
+    // None for entries loaded from disk, which are due for retransmission
+    sent: Option<Instant>,
+    attempts: u32,
+    data: Data,
+}
+
+// a line of the log on disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Store {
+        id: u64,
+        token: u16,
+        attempts: u32,
+        data: Data,
+    },
+    Remove(u64),
+}
+
+#[derive(Debug)]
+struct Writer {
+    records: mpsc::Sender<Record>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: BufferConfig,
+    entries: VecDeque<Entry>,
+    next_id: u64,
+    dropped: u64,
+    retransmitted: u64,
+    abandoned: u64,
+    writer: Option<Writer>,
+}
+
+/// Handle on the uplink buffer of a client runtime
+#[derive(Debug, Clone)]
+pub struct Buffer(Arc<Mutex<Inner>>);
+
+impl Buffer {
+    pub(crate) fn new(config: BufferConfig) -> Buffer {
+        let (entries, writer) = match &config.storage {
+            Storage::Memory => (VecDeque::new(), None),
+            Storage::Disk(path) => {
+                let entries = load(path);
+                let writer = Writer::spawn(path.clone(), config.capacity);
+                (entries, Some(writer))
+            }
+        };
+        Buffer(Arc::new(Mutex::new(Inner {
+            next_id: entries.back().map_or(0, |entry: &Entry| entry.id + 1),
+            config,
+            entries,
+            dropped: 0,
+            retransmitted: 0,
+            abandoned: 0,
+            writer,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // the buffer is never left inconsistent, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let inner = self.lock();
+        BufferStats {
+            len: inner.entries.len(),
+            capacity: inner.config.capacity,
+            dropped: inner.dropped,
+            retransmitted: inner.retransmitted,
+            abandoned: inner.abandoned,
+        }
+    }
+
+    /// Retains an uplink sent `attempts` times so far
+    pub(crate) fn store(&self, token: u16, data: &Data, attempts: u32) {
+        let mut inner = self.lock();
+        if inner.config.capacity == 0 {
+            return;
+        }
+        while inner.entries.len() >= inner.config.capacity {
+            if let Some(entry) = inner.entries.pop_front() {
+                inner.log(Record::Remove(entry.id));
+            }
+            inner.dropped += 1;
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.log(Record::Store {
+            id,
+            token,
+            attempts,
+            data: data.clone(),
+        });
+        inner.entries.push_back(Entry {
+            id,
+            token,
+            sent: Some(Instant::now()),
+            attempts,
+            data: data.clone(),
+        });
+    }
+
+    pub(crate) fn acked(&self, token: u16) {
+        let mut inner = self.lock();
+        let (acked, pending): (VecDeque<Entry>, VecDeque<Entry>) = inner
+            .entries
+            .drain(..)
+            .partition(|entry| entry.token == token);
+        inner.entries = pending;
+        for entry in acked {
+            inner.log(Record::Remove(entry.id));
+        }
+    }
+
+    /// Removes and returns the uplinks which were not acked within `timeout`,
+    /// flagged as delayed, with the number of times they were sent. They are
+    /// stored again once sent, unless they reached max_attempts.
+    pub(crate) fn take_unacked(&self, timeout: Duration) -> Vec<(u32, Data)> {
+        let mut inner = self.lock();
+        let (unacked, pending): (VecDeque<Entry>, VecDeque<Entry>) = inner
+            .entries
+            .drain(..)
+            .partition(|entry| !matches!(entry.sent, Some(sent) if sent.elapsed() < timeout));
+        inner.entries = pending;
+        for entry in &unacked {
+            inner.log(Record::Remove(entry.id));
+        }
+        let max_attempts = inner.config.max_attempts;
+        let (unacked, abandoned): (Vec<Entry>, Vec<Entry>) = unacked
+            .into_iter()
+            .partition(|entry| entry.attempts < max_attempts);
+        inner.abandoned += abandoned.len() as u64;
+        inner.retransmitted += unacked.len() as u64;
+        unacked
+            .into_iter()
+            .map(|entry| {
+                let mut data = entry.data;
+                for rxpk in data.rxpk.iter_mut().flatten() {
+                    if let RxPk::V2(rxpk) = rxpk {
+                        rxpk.delayed = Some(true);
+                    }
+                }
+                (entry.attempts, data)
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    fn log(&self, record: Record) {
+        if let Some(writer) = &self.writer {
+            // only fails once the writer gave up, which it already reported
+            let _ = writer.records.send(record);
+        }
+    }
+}
+
+impl Drop for Inner {
+    // let the writer flush what is left before the buffer is reopened
+    fn drop(&mut self) {
+        if let Some(Writer { records, thread }) = self.writer.take() {
+            drop(records);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Writer {
+    fn spawn(path: PathBuf, capacity: usize) -> Writer {
+        let (records, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if let Err(e) = write_log(&path, capacity, receiver) {
+                warn!("Unable to persist uplink buffer to {:?}: {}", path, e);
+            }
+        });
+        Writer { records, thread }
+    }
+}
+
+fn write_log(path: &Path, capacity: usize, records: mpsc::Receiver<Record>) -> std::io::Result<()> {
+    let mut written = compact(path)?;
+    let mut file = BufWriter::new(fs::OpenOptions::new().append(true).open(path)?);
+    // block for the first record of a batch, then take whatever else is queued
+    while let Ok(record) = records.recv() {
+        for record in std::iter::once(record).chain(records.try_iter()) {
+            serde_json::to_writer(&mut file, &record)?;
+            file.write_all(b"\n")?;
+            written += 1;
+        }
+        file.flush()?;
+        file.get_ref().sync_data()?;
+        if written > 2 * capacity.max(COMPACT_FLOOR) {
+            drop(file);
+            written = compact(path)?;
+            file = BufWriter::new(fs::OpenOptions::new().append(true).open(path)?);
+        }
+    }
+    Ok(())
+}
+
+// rewrites the log with the buffered uplinks only, returns their number
+fn compact(path: &Path) -> std::io::Result<usize> {
+    let entries = replay(path);
+    // write aside and rename so that a crash never leaves a truncated file
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(fs::File::create(&tmp)?);
+    for (id, (token, attempts, data)) in &entries {
+        let record = Record::Store {
+            id: *id,
+            token: *token,
+            attempts: *attempts,
+            data: data.clone(),
+        };
+        serde_json::to_writer(&mut file, &record)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(entries.len())
+}
+
+fn replay(path: &Path) -> BTreeMap<u64, (u16, u32, Data)> {
+    let mut entries = BTreeMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return entries,
+    };
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        match serde_json::from_str(&line) {
+            Ok(Record::Store {
+                id,
+                token,
+                attempts,
+                data,
+            }) => {
+                entries.insert(id, (token, attempts, data));
+            }
+            Ok(Record::Remove(id)) => {
+                entries.remove(&id);
+            }
+            // eg: the last line of a log cut short by a crash
+            Err(e) => warn!("Skipping unreadable buffered uplink: {}", e),
+        }
+    }
+    entries
+}
+
+fn load(path: &Path) -> VecDeque<Entry> {
+    replay(path)
+        .into_iter()
+        .map(|(id, (token, attempts, data))| Entry {
+            id,
+            token,
+            sent: None,
+            attempts,
+            data,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push_data::Packet;
+
+    fn v2_uplink() -> Data {
+        let json = r#"{"rxpk":[{"aesk":0,"brd":0,"codr":"4/5","data":"QAAAAEgAEtcDvK7ndmBFBg==","datr":"SF10BW125","freq":903.9,"jver":2,"modu":"LORA","rsig":[{"ant":0,"chan":0,"lsnr":10.0,"rssic":-46}],"size":16,"stat":1,"tmst":313998876}]}"#;
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_retransmit_unacked_as_delayed() {
+        let buffer = Buffer::new(BufferConfig {
+            capacity: 2,
+            max_attempts: 2,
+            storage: Storage::Memory,
+        });
+        buffer.store(1, &Packet::random().data, 1);
+        buffer.store(2, &v2_uplink(), 1);
+        buffer.store(3, &v2_uplink(), 1);
+        assert_eq!(buffer.stats().len, 2);
+        assert_eq!(buffer.stats().dropped, 1);
+
+        buffer.acked(2);
+        assert_eq!(buffer.stats().len, 1);
+        assert!(buffer.take_unacked(Duration::from_secs(60)).is_empty());
+
+        let unacked = buffer.take_unacked(Duration::from_secs(0));
+        assert_eq!(unacked.len(), 1);
+        let (attempts, data) = &unacked[0];
+        assert_eq!(*attempts, 1);
+        if let RxPk::V2(rxpk) = &data.rxpk.as_ref().unwrap()[0] {
+            assert_eq!(rxpk.delayed, Some(true));
+        } else {
+            panic!("expected a V2 rxpk");
+        }
+        assert_eq!(buffer.stats().len, 0);
+        assert_eq!(buffer.stats().retransmitted, 1);
+
+        // sent twice without ack, it is given up on
+        buffer.store(4, data, attempts + 1);
+        assert!(buffer.take_unacked(Duration::from_secs(0)).is_empty());
+        assert_eq!(buffer.stats().len, 0);
+        assert_eq!(buffer.stats().abandoned, 1);
+    }
+
+    #[test]
+    fn test_disk_buffer_survives_restart() {
+        let path = std::env::temp_dir().join(format!("semtech-udp-{}.buf", rand::random::<u32>()));
+        let config = BufferConfig {
+            capacity: 8,
+            max_attempts: 4,
+            storage: Storage::Disk(path.clone()),
+        };
+        let buffer = Buffer::new(config.clone());
+        buffer.store(1, &v2_uplink(), 1);
+        buffer.store(2, &v2_uplink(), 1);
+        buffer.acked(1);
+        drop(buffer);
+
+        let buffer = Buffer::new(config.clone());
+        assert_eq!(buffer.stats().len, 1);
+        // the token survives the restart, so a late ack still clears the uplink
+        buffer.acked(0);
+        assert_eq!(buffer.stats().len, 1);
+        buffer.acked(2);
+        assert_eq!(buffer.stats().len, 0);
+        buffer.store(3, &v2_uplink(), 3);
+        drop(buffer);
+
+        // so do the attempts
+        let buffer = Buffer::new(config);
+        let unacked = buffer.take_unacked(Duration::from_secs(60));
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(unacked[0].0, 3);
+        drop(buffer);
+        fs::remove_file(path).unwrap();
+    }
+}
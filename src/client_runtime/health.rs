@@ -0,0 +1,245 @@
+/*
+   Tracks the PUSH_ACK and PULL_ACK frames answering the datagrams we send
+   upstream, to measure round trip times and ack ratio and to derive the
+   state of the connection with the server
+*/
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+// number of recent upstream datagrams the ack ratio is computed over
+const ACK_WINDOW: usize = 20;
+// weight of the newest sample in the mean round trip time
+const RTT_SMOOTHING: f64 = 0.125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// acks arrive, but too many of the recent datagrams were not acked
+    Degraded,
+    /// nothing acked yet, or too many consecutive datagrams were not acked
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Datagram {
+    PushData,
+    PullData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    pub push_data_sent: u64,
+    /// PUSH_DATA carrying a stat report, counted in push_data_sent as well
+    pub stat_reports_sent: u64,
+    pub push_acks_received: u64,
+    pub pull_data_sent: u64,
+    pub pull_acks_received: u64,
+    /// round trip time of the latest ack
+    pub rtt: Option<Duration>,
+    /// exponentially weighted mean of the round trip time
+    pub mean_rtt: Option<Duration>,
+    /// fraction of the recent upstream datagrams which were acked
+    pub ack_ratio: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+    pub state: ConnectionState,
+    pub stats: LinkStats,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Thresholds {
+    pub ack_timeout: Duration,
+    pub degraded_ack_ratio: f64,
+    pub lost_threshold: u32,
+}
+
+#[derive(Debug)]
+struct Tracker {
+    thresholds: Thresholds,
+    // when each datagram was sent, and whether it is a retransmission
+    pending: HashMap<(Datagram, u16), (Instant, bool)>,
+    window: VecDeque<bool>,
+    missed_in_a_row: u32,
+    state: ConnectionState,
+    stats: LinkStats,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+/// Handle on the connection health of a client runtime
+#[derive(Debug, Clone)]
+pub struct Health(Arc<Mutex<Tracker>>);
+
+impl Health {
+    pub(crate) fn new(thresholds: Thresholds) -> Health {
+        let (events, _) = broadcast::channel(16);
+        Health(Arc::new(Mutex::new(Tracker {
+            thresholds,
+            pending: HashMap::new(),
+            window: VecDeque::with_capacity(ACK_WINDOW),
+            missed_in_a_row: 0,
+            state: ConnectionState::Lost,
+            stats: LinkStats::default(),
+            events,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tracker> {
+        // the tracker is never left inconsistent, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.lock().state
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.lock().stats.clone()
+    }
+
+    /// Receive an event every time the connection state changes
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.lock().events.subscribe()
+    }
+
+    pub(crate) fn sent(&self, datagram: Datagram, token: u16) {
+        let mut tracker = self.lock();
+        match datagram {
+            Datagram::PushData => tracker.stats.push_data_sent += 1,
+            Datagram::PullData => tracker.stats.pull_data_sent += 1,
+        }
+        tracker
+            .pending
+            .insert((datagram, token), (Instant::now(), false));
+    }
+
+    /// Tracks the ack of a datagram sent again, which the counters skip
+    pub(crate) fn resent(&self, datagram: Datagram, token: u16) {
+        let mut tracker = self.lock();
+        tracker
+            .pending
+            .insert((datagram, token), (Instant::now(), true));
+    }
+
+    pub(crate) fn stat_sent(&self) {
+        self.lock().stats.stat_reports_sent += 1;
+    }
+
+    pub(crate) fn acked(&self, datagram: Datagram, token: u16) {
+        let mut tracker = self.lock();
+        let (sent, resent) = match tracker.pending.remove(&(datagram, token)) {
+            Some(pending) => pending,
+            None => {
+                warn!("{:?} ack received for unknown token {}", datagram, token);
+                return;
+            }
+        };
+        let rtt = sent.elapsed();
+        match datagram {
+            _ if resent => (),
+            Datagram::PushData => tracker.stats.push_acks_received += 1,
+            Datagram::PullData => tracker.stats.pull_acks_received += 1,
+        }
+        tracker.stats.rtt = Some(rtt);
+        tracker.stats.mean_rtt = Some(match tracker.stats.mean_rtt {
+            Some(mean) => mean.mul_f64(1.0 - RTT_SMOOTHING) + rtt.mul_f64(RTT_SMOOTHING),
+            None => rtt,
+        });
+        tracker.missed_in_a_row = 0;
+        tracker.record(true);
+    }
+
+    /// Counts every datagram that outlived the ack timeout as unacknowledged
+    pub(crate) fn expire(&self) {
+        let mut tracker = self.lock();
+        let timeout = tracker.thresholds.ack_timeout;
+        let before = tracker.pending.len();
+        tracker
+            .pending
+            .retain(|_, (sent, _)| sent.elapsed() < timeout);
+        for _ in tracker.pending.len()..before {
+            tracker.missed_in_a_row += 1;
+            tracker.record(false);
+        }
+    }
+}
+
+impl Tracker {
+    fn record(&mut self, acked: bool) {
+        if self.window.len() == ACK_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(acked);
+        let acks = self.window.iter().filter(|acked| **acked).count();
+        let ack_ratio = acks as f64 / self.window.len() as f64;
+        self.stats.ack_ratio = Some(ack_ratio);
+
+        let state = if self.missed_in_a_row >= self.thresholds.lost_threshold
+            || (self.state == ConnectionState::Lost && !acked)
+        {
+            ConnectionState::Lost
+        } else if ack_ratio < self.thresholds.degraded_ack_ratio {
+            ConnectionState::Degraded
+        } else {
+            ConnectionState::Connected
+        };
+
+        if state != self.state {
+            self.state = state;
+            // nobody listening for events is fine
+            let _ = self.events.send(ConnectionEvent {
+                state,
+                stats: self.stats.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_transitions() {
+        let health = Health::new(Thresholds {
+            ack_timeout: Duration::from_secs(0),
+            degraded_ack_ratio: 0.9,
+            lost_threshold: 3,
+        });
+        let mut events = health.subscribe();
+        assert_eq!(health.state(), ConnectionState::Lost);
+
+        health.sent(Datagram::PullData, 1);
+        health.acked(Datagram::PullData, 1);
+        assert_eq!(health.state(), ConnectionState::Connected);
+        assert!(health.stats().rtt.is_some());
+
+        health.sent(Datagram::PushData, 2);
+        health.expire();
+        assert_eq!(health.state(), ConnectionState::Degraded);
+
+        health.sent(Datagram::PushData, 3);
+        health.sent(Datagram::PullData, 3);
+        health.expire();
+        assert_eq!(health.state(), ConnectionState::Lost);
+        assert_eq!(health.stats().ack_ratio, Some(0.25));
+
+        let states: Vec<ConnectionState> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| event.state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ConnectionState::Connected,
+                ConnectionState::Degraded,
+                ConnectionState::Lost
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+/*
+   Emulation of the just-in-time downlink queue of lora_pkt_fwd. Every
+   PULL_RESP is checked against a gateway profile and the packets already
+   scheduled on a virtual concentrator counter, yielding the same TX_ACK
+   errors a real packet forwarder would return
+*/
+use crate::{
+    pull_resp::TxPk,
+    time::{GpsTime, LeapSeconds},
+    tx_ack::Error as TxAckError,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+// timing constants of lora_pkt_fwd's jitqueue.c, in microseconds
+const TX_START_DELAY: i64 = 1_500;
+const TX_MARGIN_DELAY: i64 = 1_000;
+const TX_JIT_DELAY: i64 = 40_000;
+
+/// Virtual concentrator counter, a free running microsecond counter that
+/// wraps around every ~71 minutes just like `tmst` on real hardware
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    epoch: Instant,
+    offset: u32,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock {
+            epoch: Instant::now(),
+            offset: rand::random(),
+        }
+    }
+}
+
+impl Clock {
+    /// Current value of the counter, to be used as `tmst` of uplinks
+    pub fn tmst(&self) -> u32 {
+        self.tmst_at(Instant::now())
+    }
+
+    fn tmst_at(&self, now: Instant) -> u32 {
+        self.offset.wrapping_add(self.elapsed_us(now) as u32)
+    }
+
+    fn elapsed_us(&self, now: Instant) -> i64 {
+        now.saturating_duration_since(self.epoch).as_micros() as i64
+    }
+}
+
+/// Periodic beacon emitted by the emulated gateway
+#[derive(Debug, Clone)]
+pub struct Beacon {
+    /// time between two beacons, relative to the start of the counter
+    pub period: Duration,
+    /// airtime of a single beacon
+    pub duration: Duration,
+}
+
+/// Capabilities of the emulated gateway
+#[derive(Debug, Clone)]
+pub struct GatewayProfile {
+    /// minimum delay between reception of a PULL_RESP and the start of its emission
+    pub min_lead: Duration,
+    /// maximum delay between reception of a PULL_RESP and the start of its emission
+    pub max_advance: Duration,
+    /// number of downlinks that can be scheduled at once
+    pub queue_size: usize,
+    /// TX frequency range in MHz for each RF chain, indexed by `rfch`
+    pub tx_frequency: Vec<(f64, f64)>,
+    /// TX power levels in dBm the gateway can emit at
+    pub tx_power: Vec<u64>,
+    /// whether `tmms` (GPS time) downlinks are accepted
+    pub gps: bool,
+    pub beacon: Option<Beacon>,
+}
+
+impl Default for GatewayProfile {
+    fn default() -> GatewayProfile {
+        GatewayProfile {
+            min_lead: Duration::from_micros(
+                (TX_START_DELAY + TX_MARGIN_DELAY + TX_JIT_DELAY) as u64,
+            ),
+            max_advance: Duration::from_secs(3 * 128),
+            queue_size: 32,
+            tx_frequency: vec![(863.0, 928.0), (863.0, 928.0)],
+            tx_power: vec![12, 14, 16, 18, 20, 22, 24, 26, 27],
+            gps: false,
+            beacon: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct JitQueue {
+    profile: GatewayProfile,
+    clock: Clock,
+    // start and end of every scheduled emission, in microseconds since clock epoch
+    queue: Vec<(i64, i64)>,
+}
+
+impl JitQueue {
+    pub fn new(profile: GatewayProfile, clock: Clock) -> JitQueue {
+        JitQueue {
+            profile,
+            clock,
+            queue: Vec::new(),
+        }
+    }
+
+    pub fn enqueue(&mut self, txpk: &TxPk) -> Result<(), TxAckError> {
+        self.enqueue_at(txpk, Instant::now())
+    }
+
+    fn enqueue_at(&mut self, txpk: &TxPk, instant: Instant) -> Result<(), TxAckError> {
+        let now = self.clock.elapsed_us(instant);
+        self.queue.retain(|(_, end)| *end > now);
+
+        match self.profile.tx_frequency.get(txpk.rfch as usize) {
+            Some((min, max)) if txpk.freq >= *min && txpk.freq <= *max => (),
+            _ => return Err(TxAckError::InvalidTransmitFrequency),
+        }
+        if !self.profile.tx_power.contains(&txpk.powe) {
+            return Err(TxAckError::InvalidTransmitPower);
+        }
+
+        let min_lead = self.profile.min_lead.as_micros() as i64;
+        let start = if txpk.imme {
+            now + min_lead
+        } else if let Some(tmst) = txpk.get_timestamp() {
+            now + tmst.signed_duration_since(self.clock.tmst_at(instant).into())
+        } else if let Some(tmms) = txpk.get_gps_time() {
+            if !self.profile.gps {
+                return Err(TxAckError::GpsUnlocked);
+            }
+            now + (tmms.as_millis() as i64 - gps_now_ms()) * 1_000
+        } else {
+            return Err(TxAckError::SendFail);
+        };
+
+        if start - now < min_lead {
+            return Err(TxAckError::TooLate);
+        }
+        if start - now > self.profile.max_advance.as_micros() as i64 {
+            return Err(TxAckError::TooEarly);
+        }
+
+        let window = (
+            start - TX_START_DELAY - TX_MARGIN_DELAY,
+            start + txpk.time_on_air().as_micros() as i64 + TX_MARGIN_DELAY,
+        );
+        if self.queue.len() >= self.profile.queue_size
+            || self.queue.iter().any(|entry| overlaps(*entry, window))
+        {
+            return Err(TxAckError::CollisionPacket);
+        }
+        if let Some(beacon) = &self.profile.beacon {
+            let period = beacon.period.as_micros() as i64;
+            let duration = beacon.duration.as_micros() as i64;
+            if period > 0 {
+                // the first beacon which may still be on air when the window opens
+                let first = (window.0 - duration - TX_MARGIN_DELAY).div_euclid(period) + 1;
+                let beacon_start = first * period;
+                if overlaps((beacon_start, beacon_start + duration), window) {
+                    return Err(TxAckError::CollisionBeacon);
+                }
+            }
+        }
+
+        self.queue.push(window);
+        Ok(())
+    }
+}
+
+fn overlaps(a: (i64, i64), b: (i64, i64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+fn gps_now_ms() -> i64 {
+    GpsTime::from_utc(SystemTime::now(), &LeapSeconds::default())
+        .map_or(0, |gps| gps.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bandwidth, CodingRate, DataRate, Modulation, SpreadingFactor, StringOrNum};
+
+    fn txpk(tmst: u32, len: usize) -> TxPk {
+        TxPk {
+            imme: false,
+            tmst: Some(StringOrNum::N(tmst)),
+            tmms: None,
+            freq: 869.525,
+            rfch: 0,
+            powe: 14,
+            modu: Modulation::LORA,
+            datr: DataRate::new(SpreadingFactor::SF9, Bandwidth::BW125),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: len as u64,
+            data: vec![0; len],
+            ncrc: Some(true),
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn test_jit_errors() {
+        let clock = Clock::default();
+        let mut jit = JitQueue::new(GatewayProfile::default(), clock);
+        let instant = Instant::now();
+        let now = clock.tmst_at(instant);
+        let mut enqueue = |txpk: &TxPk| jit.enqueue_at(txpk, instant);
+
+        assert_eq!(enqueue(&txpk(now, 12)), Err(TxAckError::TooLate));
+        assert_eq!(
+            enqueue(&txpk(now.wrapping_add(400_000_000), 12)),
+            Err(TxAckError::TooEarly)
+        );
+        assert_eq!(enqueue(&txpk(now.wrapping_add(1_000_000), 12)), Ok(()));
+        assert_eq!(
+            enqueue(&txpk(now.wrapping_add(1_100_000), 12)),
+            Err(TxAckError::CollisionPacket)
+        );
+        assert_eq!(enqueue(&txpk(now.wrapping_add(2_000_000), 12)), Ok(()));
+
+        let mut out_of_band = txpk(now.wrapping_add(3_000_000), 12);
+        out_of_band.freq = 433.175;
+        assert_eq!(
+            enqueue(&out_of_band),
+            Err(TxAckError::InvalidTransmitFrequency)
+        );
+        let mut too_loud = txpk(now.wrapping_add(3_000_000), 12);
+        too_loud.powe = 36;
+        assert_eq!(enqueue(&too_loud), Err(TxAckError::InvalidTransmitPower));
+        // within range, but not a power the gateway is calibrated for
+        let mut uncalibrated = txpk(now.wrapping_add(3_000_000), 12);
+        uncalibrated.powe = 13;
+        assert_eq!(
+            enqueue(&uncalibrated),
+            Err(TxAckError::InvalidTransmitPower)
+        );
+        let mut gps = txpk(0, 12);
+        gps.tmst = None;
+        gps.tmms = Some(1_300_000_000);
+        assert_eq!(enqueue(&gps), Err(TxAckError::GpsUnlocked));
+    }
+
+    #[test]
+    fn test_jit_beacon_collision() {
+        let clock = Clock::default();
+        let profile = GatewayProfile {
+            beacon: Some(Beacon {
+                period: Duration::from_secs(2),
+                duration: Duration::from_millis(200),
+            }),
+            ..Default::default()
+        };
+        let mut jit = JitQueue::new(profile, clock);
+        // the counter and the beacon period start together, so the
+        // beacon goes out at 2 s and the slot after it is free
+        let instant = Instant::now();
+        let start = clock
+            .tmst_at(instant)
+            .wrapping_sub(clock.elapsed_us(instant) as u32);
+        assert_eq!(
+            jit.enqueue_at(&txpk(start.wrapping_add(2_050_000), 12), instant),
+            Err(TxAckError::CollisionBeacon)
+        );
+        assert_eq!(
+            jit.enqueue_at(&txpk(start.wrapping_add(2_500_000), 12), instant),
+            Ok(())
+        );
+    }
+}
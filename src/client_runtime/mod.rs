@@ -3,26 +3,96 @@
    run sending and receiving concurrently as tasks,
    receive downlink packets and send uplink packets easily
 */
-use crate::{parser::Parser, pull_data, Down, MacAddress, Packet, SerializablePacket, Up};
+use crate::{
+    parser::Parser, pull_data, push_data, Down, MacAddress, Packet, SerializablePacket, Up,
+};
 use log::warn;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
 use tokio::sync::{
     broadcast,
     mpsc::{self, Receiver, Sender},
 };
+use tokio::time::sleep;
 
 mod error;
 pub use error::Error;
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
+mod backoff;
+use backoff::Backoff;
+
+mod jit;
+use jit::JitQueue;
+pub use jit::{Beacon, Clock, GatewayProfile};
+
+mod health;
+pub use health::{ConnectionEvent, ConnectionState, Health, LinkStats};
+use health::{Datagram, Thresholds};
+
+mod stat;
+pub use stat::{Coordinates, Position};
+use stat::{Counters, Reporter};
+
+mod buffer;
+pub use buffer::{Buffer, BufferConfig, BufferStats, Storage};
+
 pub type RxMessage = Packet;
 pub type TxMessage = Packet;
 
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// When set, every PULL_RESP is scheduled on an emulated concentrator
+    /// and the runtime replies with the TX_ACK itself. Only accepted
+    /// downlinks are passed on to subscribers, who must not ACK them.
+    pub jit: Option<GatewayProfile>,
+    /// time between two PULL_DATA frames
+    pub keepalive_interval: Duration,
+    /// a PUSH_DATA or PULL_DATA not acked within this time counts as lost
+    pub ack_timeout: Duration,
+    /// below this ratio of acked datagrams the connection is degraded
+    pub degraded_ack_ratio: f64,
+    /// number of consecutive datagrams without ack after which the connection is lost
+    pub lost_threshold: u32,
+    /// when set, a stat report is sent upstream at this interval
+    pub stat_interval: Option<Duration>,
+    /// gateway position reported in the stat
+    pub position: Option<Position>,
+    /// custom fields appended to every stat
+    pub stat_extra: serde_json::Map<String, serde_json::Value>,
+    /// when set, uplinks are retained until acked and retransmitted once
+    /// the server answers again
+    pub buffer: Option<BufferConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            jit: None,
+            keepalive_interval: Duration::from_secs(10),
+            ack_timeout: Duration::from_secs(1),
+            degraded_ack_ratio: 0.8,
+            lost_threshold: 3,
+            stat_interval: None,
+            position: None,
+            stat_extra: serde_json::Map::new(),
+            buffer: None,
+        }
+    }
+}
+
 pub struct UdpRuntimeRx {
+    gateway_id: [u8; 8],
     sender: broadcast::Sender<RxMessage>,
+    tx_sender: Sender<TxMessage>,
     socket_recv: Arc<UdpSocket>,
+    jit: Option<JitQueue>,
+    health: Health,
+    counters: Arc<Counters>,
+    buffer: Option<Buffer>,
+    ack_timeout: Duration,
 }
 
 pub struct UdpRuntimeTx {
@@ -30,12 +100,19 @@ pub struct UdpRuntimeTx {
     receiver: Receiver<TxMessage>,
     sender: Sender<TxMessage>,
     socket_send: Arc<UdpSocket>,
+    health: Health,
+    counters: Arc<Counters>,
+    buffer: Option<Buffer>,
 }
 
 pub struct UdpRuntime {
     rx: UdpRuntimeRx,
     tx: UdpRuntimeTx,
     poll_sender: Sender<TxMessage>,
+    clock: Clock,
+    keepalive_interval: Duration,
+    ack_timeout: Duration,
+    stat: Option<(Duration, Reporter)>,
 }
 
 impl UdpRuntime {
@@ -51,8 +128,27 @@ impl UdpRuntime {
         self.rx.sender.subscribe()
     }
 
+    /// Concentrator counter that the emulated JIT queue schedules downlinks against
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Connection state and link statistics derived from PUSH_ACK and PULL_ACK frames
+    pub fn health(&self) -> Health {
+        self.tx.health.clone()
+    }
+
+    /// Occupancy of the uplink buffer, if one is configured
+    pub fn buffer(&self) -> Option<Buffer> {
+        self.tx.buffer.clone()
+    }
+
     pub async fn run(self) -> Result {
-        let (rx, tx, poll_sender) = self.split();
+        let (keepalive_interval, ack_timeout) = (self.keepalive_interval, self.ack_timeout);
+        let health = self.health();
+        let stat = self.stat;
+        let (rx, tx, poll_sender) = (self.rx, self.tx, self.poll_sender);
+        let stat_sender = poll_sender.clone();
 
         // udp_runtime_rx reads from the UDP port
         // and sends packets to the receiver channel
@@ -77,14 +173,44 @@ impl UdpRuntime {
                 if let Err(e) = poll_sender.send(packet.into()).await {
                     panic!("UdpRuntime error from sending PullData {}", e)
                 }
-                sleep(Duration::from_millis(10000)).await;
+                sleep(keepalive_interval).await;
+            }
+        });
+
+        // spawn a timer for expiring datagrams that were never acked
+        tokio::spawn(async move {
+            loop {
+                sleep(ack_timeout / 2).await;
+                health.expire();
             }
         });
 
+        // spawn a timer for sending stat reports
+        if let Some((stat_interval, mut reporter)) = stat {
+            tokio::spawn(async move {
+                loop {
+                    sleep(stat_interval).await;
+                    let packet = push_data::Packet::from_stat(reporter.report());
+                    if let Err(e) = stat_sender.send(packet.into()).await {
+                        panic!("UdpRuntime error from sending Stat {}", e)
+                    }
+                }
+            });
+        }
+
         Ok(())
     }
 
     pub async fn new(mac: [u8; 8], local: SocketAddr, host: SocketAddr) -> Result<UdpRuntime> {
+        Self::new_with_config(mac, local, host, Config::default()).await
+    }
+
+    pub async fn new_with_config(
+        mac: [u8; 8],
+        local: SocketAddr,
+        host: SocketAddr,
+        config: Config,
+    ) -> Result<UdpRuntime> {
         let socket = UdpSocket::bind(&local).await?;
         // "connecting" filters for only frames from the server
         socket.connect(host).await?;
@@ -93,44 +219,101 @@ impl UdpRuntime {
 
         let (rx_sender, _) = broadcast::channel(100);
         let (tx_sender, tx_receiver) = mpsc::channel(100);
+        let clock = Clock::default();
+        let health = Health::new(Thresholds {
+            ack_timeout: config.ack_timeout,
+            degraded_ack_ratio: config.degraded_ack_ratio,
+            lost_threshold: config.lost_threshold,
+        });
+        let counters = Arc::new(Counters::default());
+        let buffer = config.buffer.map(Buffer::new);
+        let (position, stat_extra) = (config.position, config.stat_extra);
+        let stat = config.stat_interval.map(|interval| {
+            let reporter = Reporter::new(counters.clone(), health.clone(), position, stat_extra);
+            (interval, reporter)
+        });
 
         Ok(UdpRuntime {
             rx: UdpRuntimeRx {
+                gateway_id: mac,
                 sender: rx_sender,
+                tx_sender: tx_sender.clone(),
                 socket_recv,
+                jit: config.jit.map(|profile| JitQueue::new(profile, clock)),
+                health: health.clone(),
+                counters: counters.clone(),
+                buffer: buffer.clone(),
+                ack_timeout: config.ack_timeout,
             },
             poll_sender: tx_sender.clone(),
+            clock,
+            keepalive_interval: config.keepalive_interval,
+            ack_timeout: config.ack_timeout,
+            stat,
             tx: UdpRuntimeTx {
                 gateway_id: mac,
                 receiver: tx_receiver,
                 sender: tx_sender,
                 socket_send,
+                health,
+                counters,
+                buffer,
             },
         })
     }
 }
 
-use std::time::Duration;
-use tokio::time::sleep;
-
 impl UdpRuntimeRx {
-    pub async fn run(self) -> Result {
+    pub async fn run(mut self) -> Result {
         let mut buf = vec![0u8; 1024];
+        let mut backoff = Backoff::default();
         loop {
             match self.socket_recv.recv(&mut buf).await {
                 Ok(n) => {
+                    backoff.reset();
                     match Packet::parse(&buf[0..n]) {
                         Ok(packet) => {
                             match packet {
                                 Packet::Up(_) => panic!("Should not be receiving any up packets"),
                                 Packet::Down(down) => match down.clone() {
                                     Down::PullResp(pull_resp) => {
+                                        self.counters.downlink_received();
+                                        if let Some(jit) = &mut self.jit {
+                                            let mac = MacAddress::new(&self.gateway_id);
+                                            let result = jit.enqueue(&pull_resp.data.txpk);
+                                            let ack = match result {
+                                                Ok(()) => {
+                                                    pull_resp.clone().into_ack_for_gateway(mac)
+                                                }
+                                                Err(e) => {
+                                                    warn!("Rejecting downlink: {}", e);
+                                                    pull_resp
+                                                        .clone()
+                                                        .into_nack_with_error_for_gateway(e, mac)
+                                                }
+                                            };
+                                            self.tx_sender.send(ack.into()).await?;
+                                            if result.is_err() {
+                                                continue;
+                                            }
+                                        }
                                         // send downlinks to LoRaWAN layer
                                         self.sender.send(pull_resp.clone().into()).unwrap();
                                     }
-                                    Down::PullAck(_) | Down::PushAck(_) => {
+                                    Down::PullAck(ack) => {
+                                        self.health.acked(Datagram::PullData, ack.random_token);
+                                        self.retransmit().await?;
+                                        // send downlinks to LoRaWAN layer
+                                        self.sender.send(Packet::Down(down)).unwrap();
+                                    }
+                                    Down::PushAck(ack) => {
+                                        self.health.acked(Datagram::PushData, ack.random_token);
+                                        if let Some(buffer) = &self.buffer {
+                                            buffer.acked(ack.random_token);
+                                        }
+                                        self.retransmit().await?;
                                         // send downlinks to LoRaWAN layer
-                                        self.sender.send(Packet::Down(down.clone())).unwrap();
+                                        self.sender.send(Packet::Down(down)).unwrap();
                                     }
                                 },
                             }
@@ -140,18 +323,43 @@ impl UdpRuntimeRx {
                     }
                 }
                 Err(e) => {
-                    warn!("Socket receive error: {}", e);
-                    // back off of CPU
-                    sleep(Duration::from_secs(10)).await;
+                    let delay = backoff.next_delay();
+                    warn!("Socket receive error, retrying in {:?}: {}", delay, e);
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    // the server answers, so send again the uplinks it never acked. They go
+    // straight to the socket shared with UdpRuntimeTx, which would count them
+    // in the stats a second time
+    async fn retransmit(&self) -> Result {
+        if let Some(buffer) = &self.buffer {
+            let mut buf = vec![0u8; 1024];
+            for (attempts, data) in buffer.take_unacked(self.ack_timeout) {
+                let packet = push_data::Packet {
+                    random_token: rand::random(),
+                    gateway_mac: MacAddress::new(&self.gateway_id),
+                    data,
+                };
+                self.health.resent(Datagram::PushData, packet.random_token);
+                buffer.store(packet.random_token, &packet.data, attempts + 1);
+                let n = packet.serialize(&mut buf)? as usize;
+                // still buffered, so sent again after the next ack
+                if let Err(e) = self.socket_recv.send(&buf[..n]).await {
+                    warn!("Socket error on retransmission: {}", e);
                 }
             }
         }
+        Ok(())
     }
 }
 
 impl UdpRuntimeTx {
     pub async fn run(mut self) -> Result {
         let mut buf = vec![0u8; 1024];
+        let mut backoff = Backoff::default();
         loop {
             let tx = self.receiver.recv().await;
             if let Some(mut data) = tx {
@@ -160,22 +368,41 @@ impl UdpRuntimeTx {
                         up.set_gateway_mac(MacAddress::new(&self.gateway_id));
                         match up {
                             Up::PushData(ref mut push_data) => {
-                                push_data.random_token = rand::random()
+                                push_data.random_token = rand::random();
+                                self.health.sent(Datagram::PushData, push_data.random_token);
+                                if push_data.data.stat.is_some() {
+                                    self.health.stat_sent();
+                                }
+                                if let Some(rxpk) = &push_data.data.rxpk {
+                                    self.counters.forwarded(rxpk);
+                                    if let Some(buffer) = &self.buffer {
+                                        buffer.store(push_data.random_token, &push_data.data, 1);
+                                    }
+                                }
                             }
                             Up::PullData(ref mut pull_data) => {
-                                pull_data.random_token = rand::random()
+                                pull_data.random_token = rand::random();
+                                self.health.sent(Datagram::PullData, pull_data.random_token);
+                            }
+                            Up::TxAck(tx_ack) => {
+                                if tx_ack.get_result().is_ok() {
+                                    self.counters.downlink_emitted();
+                                }
                             }
-                            Up::TxAck(_) => (),
                         }
                     }
                     Packet::Down(_) => panic!("Should not be sending any down packets"),
                 }
 
                 let n = data.serialize(&mut buf)? as usize;
-                if let Err(e) = self.socket_send.send(&buf[..n]).await {
-                    warn!("Socket error: {}", e);
-                    // back off of CPU
-                    sleep(Duration::from_secs(10)).await;
+                match self.socket_send.send(&buf[..n]).await {
+                    Ok(_) => backoff.reset(),
+                    Err(e) => {
+                        // buffered uplinks are sent again once the server answers
+                        let delay = backoff.next_delay();
+                        warn!("Socket error, retrying in {:?}: {}", delay, e);
+                        sleep(delay).await;
+                    }
                 }
             }
         }
@@ -0,0 +1,168 @@
+/*
+   Maintains the counters of the periodic stat report from the traffic
+   going through the runtime, the way lora_pkt_fwd does for its own
+*/
+use super::health::{Health, LinkStats};
+use crate::{
+    push_data::{RxPk, Stat, CRC},
+    time,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    /// degrees, North is positive
+    pub latitude: f64,
+    /// degrees, East is positive
+    pub longitude: f64,
+    /// meters
+    pub altitude: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Position {
+    Static(Coordinates),
+    /// latest fix of a GPS, None while there is no fix
+    Live(watch::Receiver<Option<Coordinates>>),
+}
+
+impl Position {
+    fn coordinates(&self) -> Option<Coordinates> {
+        match self {
+            Position::Static(coordinates) => Some(*coordinates),
+            Position::Live(receiver) => *receiver.borrow(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    rxnb: AtomicU64,
+    rxok: AtomicU64,
+    rxfw: AtomicU64,
+    dwnb: AtomicU64,
+    txnb: AtomicU64,
+}
+
+impl Counters {
+    pub fn forwarded(&self, rxpk: &[RxPk]) {
+        let ok = rxpk
+            .iter()
+            .filter(|rxpk| *rxpk.get_crc_status() == CRC::OK)
+            .count() as u64;
+        self.rxnb.fetch_add(rxpk.len() as u64, Ordering::Relaxed);
+        self.rxok.fetch_add(ok, Ordering::Relaxed);
+        self.rxfw.fetch_add(rxpk.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn downlink_received(&self) {
+        self.dwnb.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn downlink_emitted(&self) {
+        self.txnb.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct Reporter {
+    counters: Arc<Counters>,
+    health: Health,
+    position: Option<Position>,
+    extra: serde_json::Map<String, serde_json::Value>,
+    last_link: LinkStats,
+}
+
+impl Reporter {
+    pub fn new(
+        counters: Arc<Counters>,
+        health: Health,
+        position: Option<Position>,
+        extra: serde_json::Map<String, serde_json::Value>,
+    ) -> Reporter {
+        Reporter {
+            counters,
+            health,
+            position,
+            extra,
+            last_link: LinkStats::default(),
+        }
+    }
+
+    /// Builds the report for the interval since the previous call
+    pub fn report(&mut self) -> Stat {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        let link = self.health.stats();
+        let sent = link.push_data_sent - self.last_link.push_data_sent;
+        let acked = link.push_acks_received - self.last_link.push_acks_received;
+        self.last_link = link;
+        let coordinates = self.position.as_ref().and_then(Position::coordinates);
+
+        Stat {
+            time: time::utc_expanded(SystemTime::now()),
+            lati: coordinates.map(|c| c.latitude),
+            long: coordinates.map(|c| c.longitude),
+            alti: coordinates.map(|c| c.altitude),
+            rxnb: take(&self.counters.rxnb),
+            rxok: take(&self.counters.rxok),
+            rxfw: take(&self.counters.rxfw),
+            ackr: if sent > 0 {
+                Some(100.0 * acked.min(sent) as f64 / sent as f64)
+            } else {
+                None
+            },
+            dwnb: take(&self.counters.dwnb),
+            txnb: take(&self.counters.txnb),
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::health::Thresholds;
+    use super::*;
+    use crate::push_data::Packet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_report_resets_counters() {
+        let counters = Arc::new(Counters::default());
+        let health = Health::new(Thresholds {
+            ack_timeout: Duration::from_secs(1),
+            degraded_ack_ratio: 0.8,
+            lost_threshold: 3,
+        });
+        let (gps, fix) = watch::channel(None);
+        let mut extra = serde_json::Map::new();
+        extra.insert("temp".into(), 21.into());
+        let mut reporter =
+            Reporter::new(counters.clone(), health, Some(Position::Live(fix)), extra);
+
+        let rxpk = Packet::random().data.rxpk.unwrap();
+        counters.forwarded(&rxpk);
+        counters.forwarded(&rxpk);
+        counters.downlink_received();
+
+        let stat = reporter.report();
+        assert_eq!((stat.rxnb, stat.rxok, stat.rxfw), (2, 2, 2));
+        assert_eq!((stat.dwnb, stat.txnb), (1, 0));
+        assert_eq!(stat.ackr, None);
+        assert_eq!(stat.lati, None);
+        let json = serde_json::to_string(&stat).unwrap();
+        assert!(json.ends_with(",\"temp\":21}"));
+
+        gps.send(Some(Coordinates {
+            latitude: 46.5,
+            longitude: 6.6,
+            altitude: 372,
+        }))
+        .unwrap();
+        let stat = reporter.report();
+        assert_eq!(stat.rxnb, 0);
+        assert_eq!(stat.lati, Some(46.5));
+        assert_eq!(stat.alti, Some(372));
+    }
+}
@@ -4,11 +4,30 @@ extern crate arrayref;
 mod packet;
 pub use packet::*;
 
+pub mod class_b;
+pub mod region;
+pub mod rx_windows;
+
+#[cfg(feature = "lorawan")]
+pub mod lorawan;
+
+#[cfg(feature = "routing")]
+pub mod routing;
+
 #[cfg(feature = "server")]
 pub mod server_runtime;
 
 #[cfg(feature = "client")]
 pub mod client_runtime;
 
+#[cfg(feature = "simulator")]
+pub mod simulator;
+
+#[cfg(feature = "mux")]
+pub mod mux;
+
+#[cfg(feature = "proxy")]
+pub mod proxy;
+
 #[cfg(test)]
 mod tests;
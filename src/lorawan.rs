@@ -0,0 +1,399 @@
+/*
+   Read-only view over the LoRaWAN PHYPayload carried in the data field of
+   rxpk and txpk. Nothing is copied or decrypted: accessors read the fields
+   straight out of the borrowed bytes, in the layout of LoRaWAN 1.0/1.1
+
+   PHYPayload = MHDR | MACPayload | MIC
+   MACPayload = FHDR | FPort | FRMPayload
+   FHDR       = DevAddr | FCtrl | FCnt | FOpts
+*/
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("empty PHYPayload")]
+    Empty,
+    #[error("unsupported LoRaWAN major version {0}")]
+    UnsupportedMajor(u8),
+    #[error("{0} of {1} bytes is too short")]
+    TooShort(MType, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest,
+    JoinAccept,
+    UnconfirmedDataUp,
+    UnconfirmedDataDown,
+    ConfirmedDataUp,
+    ConfirmedDataDown,
+    RejoinRequest,
+    Proprietary,
+}
+
+impl MType {
+    pub fn is_uplink(&self) -> bool {
+        matches!(
+            self,
+            MType::JoinRequest
+                | MType::UnconfirmedDataUp
+                | MType::ConfirmedDataUp
+                | MType::RejoinRequest
+        )
+    }
+}
+
+impl fmt::Display for MType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mhdr(u8);
+
+impl Mhdr {
+    pub fn mtype(&self) -> MType {
+        match self.0 >> 5 {
+            0 => MType::JoinRequest,
+            1 => MType::JoinAccept,
+            2 => MType::UnconfirmedDataUp,
+            3 => MType::UnconfirmedDataDown,
+            4 => MType::ConfirmedDataUp,
+            5 => MType::ConfirmedDataDown,
+            6 => MType::RejoinRequest,
+            _ => MType::Proprietary,
+        }
+    }
+
+    /// 0 for LoRaWAN R1, the only version defined
+    pub fn major(&self) -> u8 {
+        self.0 & 0x03
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhyPayload<'a>(&'a [u8]);
+
+impl<'a> PhyPayload<'a> {
+    /// Checks the frame is long enough for the fields of its MType
+    pub fn new(bytes: &'a [u8]) -> Result<PhyPayload<'a>, Error> {
+        let mhdr = Mhdr(*bytes.first().ok_or(Error::Empty)?);
+        if mhdr.major() != 0 {
+            return Err(Error::UnsupportedMajor(mhdr.major()));
+        }
+        let mtype = mhdr.mtype();
+        let min_len = match mtype {
+            MType::JoinRequest => 23,
+            MType::JoinAccept => 17,
+            MType::RejoinRequest => match bytes.get(1) {
+                Some(1) => 24,
+                _ => 19,
+            },
+            MType::Proprietary => 1,
+            // MHDR, DevAddr, FCtrl, FCnt, FOpts and MIC
+            _ => 12 + bytes.get(5).map_or(0, |fctrl| (fctrl & 0x0f) as usize),
+        };
+        if bytes.len() < min_len {
+            return Err(Error::TooShort(mtype, bytes.len()));
+        }
+        Ok(PhyPayload(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn mhdr(&self) -> Mhdr {
+        Mhdr(self.0[0])
+    }
+
+    pub fn mtype(&self) -> MType {
+        self.mhdr().mtype()
+    }
+
+    /// None for proprietary frames, which have no defined MIC
+    pub fn mic(&self) -> Option<[u8; 4]> {
+        if self.mtype() == MType::Proprietary {
+            return None;
+        }
+        Some(*array_ref![self.0, self.0.len() - 4, 4])
+    }
+
+    pub fn payload(&self) -> Payload<'a> {
+        let mtype = self.mtype();
+        // proprietary frames have no MIC to strip and may be shorter than one
+        if mtype == MType::Proprietary {
+            return Payload::Proprietary(&self.0[1..]);
+        }
+        // everything between the MHDR and the MIC
+        let mac_payload = &self.0[1..self.0.len() - 4];
+        match mtype {
+            MType::JoinRequest => Payload::JoinRequest(JoinRequest(mac_payload)),
+            MType::JoinAccept => Payload::JoinAccept(JoinAccept(mac_payload)),
+            MType::RejoinRequest => Payload::RejoinRequest(RejoinRequest(mac_payload)),
+            _ => Payload::Data(DataPayload {
+                bytes: mac_payload,
+                uplink: mtype.is_uplink(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for PhyPayload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mtype())?;
+        match self.payload() {
+            Payload::JoinRequest(join) => write!(
+                f,
+                " JoinEUI={:016X} DevEUI={:016X} DevNonce={}",
+                join.join_eui(),
+                join.dev_eui(),
+                join.dev_nonce()
+            ),
+            Payload::Data(data) => {
+                write!(f, " DevAddr={:08X} FCnt={}", data.dev_addr(), data.fcnt())?;
+                if let Some(fport) = data.fport() {
+                    write!(f, " FPort={}", fport)?;
+                }
+                Ok(())
+            }
+            Payload::JoinAccept(_) | Payload::RejoinRequest(_) | Payload::Proprietary(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload<'a> {
+    JoinRequest(JoinRequest<'a>),
+    JoinAccept(JoinAccept<'a>),
+    Data(DataPayload<'a>),
+    RejoinRequest(RejoinRequest<'a>),
+    /// everything after the MHDR, proprietary frames have no defined layout
+    Proprietary(&'a [u8]),
+}
+
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinRequest<'a>(&'a [u8]);
+
+impl JoinRequest<'_> {
+    pub fn join_eui(&self) -> u64 {
+        le(&self.0[0..8])
+    }
+
+    pub fn dev_eui(&self) -> u64 {
+        le(&self.0[8..16])
+    }
+
+    pub fn dev_nonce(&self) -> u16 {
+        le(&self.0[16..18]) as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RejoinRequest<'a>(&'a [u8]);
+
+impl RejoinRequest<'_> {
+    pub fn rejoin_type(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// only carried by rejoin requests of type 0 and 2
+    pub fn net_id(&self) -> Option<u32> {
+        match self.rejoin_type() {
+            0 | 2 => Some(le(&self.0[1..4]) as u32),
+            _ => None,
+        }
+    }
+
+    /// only carried by rejoin requests of type 1
+    pub fn join_eui(&self) -> Option<u64> {
+        match self.rejoin_type() {
+            1 => Some(le(&self.0[1..9])),
+            _ => None,
+        }
+    }
+
+    pub fn dev_eui(&self) -> Option<u64> {
+        match self.rejoin_type() {
+            0 | 2 => Some(le(&self.0[4..12])),
+            1 => Some(le(&self.0[9..17])),
+            _ => None,
+        }
+    }
+}
+
+/// Join accepts are encrypted over the air, so the fields only make sense
+/// on a payload which was decrypted beforehand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinAccept<'a>(&'a [u8]);
+
+impl<'a> JoinAccept<'a> {
+    pub fn join_nonce(&self) -> u32 {
+        le(&self.0[0..3]) as u32
+    }
+
+    pub fn home_net_id(&self) -> u32 {
+        le(&self.0[3..6]) as u32
+    }
+
+    pub fn dev_addr(&self) -> u32 {
+        le(&self.0[6..10]) as u32
+    }
+
+    pub fn dl_settings(&self) -> u8 {
+        self.0[10]
+    }
+
+    pub fn rx_delay(&self) -> u8 {
+        self.0[11]
+    }
+
+    pub fn cf_list(&self) -> Option<&'a [u8]> {
+        self.0.get(12..).filter(|cf_list| !cf_list.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FCtrl {
+    byte: u8,
+    uplink: bool,
+}
+
+impl FCtrl {
+    pub fn adr(&self) -> bool {
+        self.byte & 0x80 != 0
+    }
+
+    /// only defined on uplinks
+    pub fn adr_ack_req(&self) -> bool {
+        self.uplink && self.byte & 0x40 != 0
+    }
+
+    pub fn ack(&self) -> bool {
+        self.byte & 0x20 != 0
+    }
+
+    /// only defined on downlinks
+    pub fn fpending(&self) -> bool {
+        !self.uplink && self.byte & 0x10 != 0
+    }
+
+    /// only defined on uplinks
+    pub fn class_b(&self) -> bool {
+        self.uplink && self.byte & 0x10 != 0
+    }
+
+    pub fn fopts_len(&self) -> usize {
+        (self.byte & 0x0f) as usize
+    }
+}
+
+/// MACPayload of a data frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataPayload<'a> {
+    bytes: &'a [u8],
+    uplink: bool,
+}
+
+impl<'a> DataPayload<'a> {
+    pub fn dev_addr(&self) -> u32 {
+        le(&self.bytes[0..4]) as u32
+    }
+
+    pub fn fctrl(&self) -> FCtrl {
+        FCtrl {
+            byte: self.bytes[4],
+            uplink: self.uplink,
+        }
+    }
+
+    /// the 16 least significant bits of the frame counter
+    pub fn fcnt(&self) -> u16 {
+        le(&self.bytes[5..7]) as u16
+    }
+
+    pub fn fopts(&self) -> &'a [u8] {
+        &self.bytes[7..7 + self.fctrl().fopts_len()]
+    }
+
+    /// None when the frame has neither FPort nor FRMPayload
+    pub fn fport(&self) -> Option<u8> {
+        self.bytes.get(7 + self.fctrl().fopts_len()).copied()
+    }
+
+    /// encrypted, empty when there is no FPort
+    pub fn frm_payload(&self) -> &'a [u8] {
+        self.bytes
+            .get(8 + self.fctrl().fopts_len()..)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_frame() {
+        // unconfirmed data up, ADR, one byte of FOpts, FPort 1
+        let frame = [
+            0x40, 0x34, 0x12, 0x01, 0x26, 0x81, 0x2a, 0x00, 0x02, 0x01, 0xaa, 0xbb, 0x01, 0x02,
+            0x03, 0x04,
+        ];
+        let phy = PhyPayload::new(&frame).unwrap();
+        assert_eq!(phy.mtype(), MType::UnconfirmedDataUp);
+        assert_eq!(phy.mic(), Some([0x01, 0x02, 0x03, 0x04]));
+        if let Payload::Data(data) = phy.payload() {
+            assert_eq!(data.dev_addr(), 0x2601_1234);
+            assert!(data.fctrl().adr());
+            assert_eq!(data.fopts(), &[0x02]);
+            assert_eq!(data.fport(), Some(1));
+            assert_eq!(data.frm_payload(), &[0xaa, 0xbb]);
+        } else {
+            panic!("expected a data frame");
+        }
+        assert_eq!(
+            phy.to_string(),
+            "UnconfirmedDataUp DevAddr=26011234 FCnt=42 FPort=1"
+        );
+
+        // the FOpts announced by FCtrl do not fit
+        assert_eq!(
+            PhyPayload::new(&frame[..12]),
+            Err(Error::TooShort(MType::UnconfirmedDataUp, 12))
+        );
+    }
+
+    #[test]
+    fn test_join_request() {
+        let mut frame = vec![0x00];
+        frame.extend_from_slice(&0x70b3_d57e_d000_0001u64.to_le_bytes());
+        frame.extend_from_slice(&0x0004_a30b_001c_0530u64.to_le_bytes());
+        frame.extend_from_slice(&[0x39, 0x05, 1, 2, 3, 4]);
+        let phy = PhyPayload::new(&frame).unwrap();
+        assert_eq!(
+            phy.to_string(),
+            "JoinRequest JoinEUI=70B3D57ED0000001 DevEUI=0004A30B001C0530 DevNonce=1337"
+        );
+        assert_eq!(PhyPayload::new(&[]), Err(Error::Empty));
+        assert_eq!(PhyPayload::new(&[0x01]), Err(Error::UnsupportedMajor(1)));
+    }
+
+    #[test]
+    fn test_short_proprietary() {
+        let phy = PhyPayload::new(&[0xe0, 0x01]).unwrap();
+        assert_eq!(phy.mic(), None);
+        assert_eq!(phy.payload(), Payload::Proprietary(&[0x01]));
+        assert_eq!(phy.to_string(), "Proprietary");
+        assert_eq!(
+            PhyPayload::new(&[0xe0]).unwrap().payload(),
+            Payload::Proprietary(&[])
+        );
+    }
+}
@@ -0,0 +1,14 @@
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("client runtime error")]
+    ClientRuntime(#[from] crate::client_runtime::Error),
+    #[error("server runtime error")]
+    ServerRuntime(#[from] crate::server_runtime::Error),
+    #[error("tokio::mpsc send error")]
+    SendError(#[from] mpsc::error::SendError<crate::Packet>),
+    #[error("mux configured with no upstreams")]
+    NoUpstreams,
+}
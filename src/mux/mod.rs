@@ -0,0 +1,390 @@
+/*
+   This module multiplexes packet forwarders to several servers. Gateways
+   connect to the mux as they would to a server; for every gateway the mux
+   opens one client_runtime::UdpRuntime per upstream server, fans the uplinks
+   out to the upstreams whose filter accepts them and dispatches the
+   downlinks of every upstream to the gateway. The TX_ACK reported by the
+   gateway is relayed to the upstream which issued the downlink only.
+   The stat reports of the gateways are not relayed: an upstream receives the
+   ones of the mux's own client runtime, if its config sets a stat_interval
+*/
+#[cfg(feature = "routing")]
+use crate::routing::RoutingTable;
+use crate::{
+    client_runtime,
+    push_data::{self, RxPk, RxPkV1},
+    server_runtime::{self, ClientRx, ClientTx},
+    tx_ack, Down, MacAddress, Packet, UnparsedFrame,
+};
+use futures::future::join_all;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc::Sender};
+
+mod error;
+pub use error::Error;
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Decides whether an uplink received from a gateway is sent to an upstream
+pub type Filter = Arc<dyn Fn(&MacAddress, &RxPk) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Upstream {
+    /// server the gateways are connected to
+    pub host: SocketAddr,
+    /// when set, only the uplinks it accepts are sent to this server
+    pub filter: Option<Filter>,
+    /// settings of the client runtime connecting each gateway to this server,
+    /// its JIT profile is ignored since the gateway acks downlinks itself
+    pub config: client_runtime::Config,
+    /// for servers which predate jver 2, multi-antenna uplinks are sent as
+    /// version 1 rxpk with their best reception
+    pub v1_only: bool,
+}
+
+impl Upstream {
+    pub fn new(host: SocketAddr) -> Upstream {
+        Upstream {
+            host,
+            filter: None,
+            config: client_runtime::Config::default(),
+            v1_only: false,
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Upstream {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Only send this server the uplinks the table routes to its host
+    #[cfg(feature = "routing")]
+    pub fn with_routing(self, table: Arc<RoutingTable<SocketAddr>>) -> Upstream {
+        let host = self.host;
+        self.with_filter(Arc::new(move |_, rxpk| {
+            table.route_rxpk(rxpk) == Some(&host)
+        }))
+    }
+
+    pub fn v1_only(mut self) -> Upstream {
+        self.v1_only = true;
+        self
+    }
+
+    fn accepts(&self, mac: &MacAddress, rxpk: &RxPk) -> bool {
+        match &self.filter {
+            Some(filter) => filter(mac, rxpk),
+            None => true,
+        }
+    }
+
+    fn adapt(&self, rxpk: &RxPk) -> RxPk {
+        match rxpk {
+            RxPk::V2(pk) if self.v1_only => match RxPkV1::try_from(pk.clone()) {
+                Ok(pk) => RxPk::V1(pk),
+                Err(e) => {
+                    warn!("Uplink relayed to {} as is: {}", self.host, e);
+                    rxpk.clone()
+                }
+            },
+            _ => rxpk.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// address the gateways connect to
+    pub listen: SocketAddr,
+    pub upstreams: Vec<Upstream>,
+    /// how long to wait for the TX_ACK of the gateway before reporting
+    /// the downlink as failed to the upstream
+    pub downlink_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// first frame of a gateway, which is now connected to every upstream
+    GatewayConnected(MacAddress, SocketAddr),
+    /// downlink of an upstream dispatched to a gateway, along with the
+    /// result relayed to that upstream
+    DownlinkDispatched(
+        MacAddress,
+        SocketAddr,
+        std::result::Result<(), tx_ack::Error>,
+    ),
+    UnableToParseUdpFrame(UnparsedFrame),
+    /// an uplink could not be handed to the runtime of an upstream, eg:
+    /// because the gateway failed to connect to it
+    UplinkDropped(MacAddress, SocketAddr),
+}
+
+pub struct Mux {
+    upstreams: Vec<Upstream>,
+    downlink_timeout: Duration,
+    server_rx: ClientRx,
+    server_tx: ClientTx,
+    local_addr: SocketAddr,
+    // one sender per upstream, in the order of the upstreams
+    gateways: HashMap<MacAddress, Vec<Sender<Packet>>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Mux {
+    pub async fn new(config: Config) -> Result<Mux> {
+        if config.upstreams.is_empty() {
+            return Err(Error::NoUpstreams);
+        }
+        let server = server_runtime::UdpRuntime::new(config.listen).await?;
+        let local_addr = server.local_addr();
+        let (server_rx, server_tx) = server.split();
+        let (events, _) = broadcast::channel(1024);
+        Ok(Mux {
+            upstreams: config.upstreams,
+            downlink_timeout: config.downlink_timeout,
+            server_rx,
+            server_tx,
+            local_addr,
+            gateways: HashMap::new(),
+            events,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Address the gateways connect to, eg: the port picked when listening on port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn run(mut self) -> Result {
+        loop {
+            match self.server_rx.recv().await {
+                server_runtime::Event::NewClient((mac, addr)) => {
+                    if let Err(e) = self.connect(mac).await {
+                        warn!("Unable to connect {} upstream: {}", mac, e);
+                        continue;
+                    }
+                    // nobody listening for events is fine
+                    let _ = self.events.send(Event::GatewayConnected(mac, addr));
+                }
+                server_runtime::Event::UpdateClient((mac, addr)) => {
+                    debug!("{} moved to {}", mac, addr);
+                }
+                server_runtime::Event::PacketReceived(rxpk, mac) => {
+                    // a PUSH_DATA may come before the first PULL_DATA
+                    if let Err(e) = self.connect(mac).await {
+                        warn!("Unable to connect {} upstream: {}", mac, e);
+                        continue;
+                    }
+                    self.fan_out(mac, &rxpk).await;
+                }
+                server_runtime::Event::UnableToParseUdpFrame(frame) => {
+                    let _ = self.events.send(Event::UnableToParseUdpFrame(frame));
+                }
+                server_runtime::Event::NoClientWithMac(_, mac) => {
+                    // the pending dispatch fails and reports it upstream
+                    warn!("Downlink for disconnected gateway {}", mac);
+                }
+            }
+        }
+    }
+
+    // sends the uplink to every upstream accepting it at once, so that a slow
+    // upstream does not hold back the others
+    async fn fan_out(&self, mac: MacAddress, rxpk: &RxPk) {
+        let sends = self
+            .upstreams
+            .iter()
+            .zip(&self.gateways[&mac])
+            .filter(|(upstream, _)| upstream.accepts(&mac, rxpk))
+            .map(|(upstream, sender)| async move {
+                let mut packet = push_data::Packet::from_rxpk(upstream.adapt(rxpk));
+                packet.gateway_mac = mac;
+                if sender.send(packet.into()).await.is_err() {
+                    warn!("{} unable to relay uplink to {}", mac, upstream.host);
+                    let _ = self.events.send(Event::UplinkDropped(mac, upstream.host));
+                }
+            });
+        join_all(sends).await;
+    }
+
+    async fn connect(&mut self, mac: MacAddress) -> Result {
+        if self.gateways.contains_key(&mac) {
+            return Ok(());
+        }
+        let mut senders = Vec::with_capacity(self.upstreams.len());
+        for upstream in &self.upstreams {
+            let local = if upstream.host.is_ipv4() {
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+            } else {
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+            };
+            let config = client_runtime::Config {
+                jit: None,
+                ..upstream.config.clone()
+            };
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(mac.bytes());
+            let udp_runtime =
+                client_runtime::UdpRuntime::new_with_config(bytes, local, upstream.host, config)
+                    .await?;
+            let (receiver, sender) = (udp_runtime.subscribe(), udp_runtime.publish_to());
+            udp_runtime.run().await?;
+
+            tokio::spawn(downlinker(
+                mac,
+                upstream.host,
+                self.downlink_timeout,
+                receiver,
+                sender.clone(),
+                self.server_tx.clone(),
+                self.events.clone(),
+            ));
+            senders.push(sender);
+        }
+        self.gateways.insert(mac, senders);
+        Ok(())
+    }
+}
+
+// dispatches the downlinks of one upstream to one gateway
+async fn downlinker(
+    mac: MacAddress,
+    upstream: SocketAddr,
+    timeout: Duration,
+    mut receiver: broadcast::Receiver<Packet>,
+    sender: Sender<Packet>,
+    mut server_tx: ClientTx,
+    events: broadcast::Sender<Event>,
+) {
+    loop {
+        let packet = match receiver.recv().await {
+            Ok(Packet::Down(Down::PullResp(packet))) => packet,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(n)) => {
+                warn!("{} dropped {} frames from {}", mac, n, upstream);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let downlink = server_tx.prepare_downlink(Some(packet.data.txpk.clone()), mac);
+        let (sender, events) = (sender.clone(), events.clone());
+        // dispatch concurrently, the gateway may schedule several downlinks
+        tokio::spawn(async move {
+            let result = downlink.dispatch(Some(timeout)).await.map_err(|e| match e {
+                server_runtime::Error::Ack(e) => e,
+                // the gateway never answered or is not connected
+                _ => tx_ack::Error::SendFail,
+            });
+            let ack = match result {
+                Ok(()) => packet.into_ack_for_gateway(mac),
+                Err(e) => packet.into_nack_with_error_for_gateway(e, mac),
+            };
+            if sender.send(ack.into()).await.is_err() {
+                warn!("{} unable to relay TX_ACK to {}", mac, upstream);
+            }
+            let _ = events.send(Event::DownlinkDispatched(mac, upstream, result));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pull_resp::TxPk, CodingRate, DataRate, Modulation, StringOrNum};
+
+    fn txpk() -> TxPk {
+        TxPk {
+            imme: true,
+            tmst: Some(StringOrNum::N(0)),
+            tmms: None,
+            freq: 923.3,
+            rfch: 0,
+            powe: 20,
+            modu: Modulation::LORA,
+            datr: DataRate::default(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: 2,
+            data: vec![1, 2],
+            ncrc: None,
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_and_ack_routing() {
+        let any_port = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut upstream_a = server_runtime::UdpRuntime::new(any_port).await.unwrap();
+        let mut upstream_b = server_runtime::UdpRuntime::new(any_port).await.unwrap();
+        let reject_all: Filter = Arc::new(|_, _| false);
+        let mux = Mux::new(Config {
+            listen: any_port,
+            upstreams: vec![
+                Upstream::new(upstream_a.local_addr()),
+                Upstream::new(upstream_b.local_addr()).with_filter(reject_all),
+            ],
+            downlink_timeout: Duration::from_secs(1),
+        })
+        .await
+        .unwrap();
+        let listen = mux.local_addr();
+        tokio::spawn(mux.run());
+
+        let mac = [1, 2, 3, 4, 5, 6, 7, 8];
+        let gateway = client_runtime::UdpRuntime::new(mac, any_port, listen)
+            .await
+            .unwrap();
+        let (mut downlinks, uplinks) = (gateway.subscribe(), gateway.publish_to());
+        let uplinks_sender = uplinks.clone();
+        gateway.run().await.unwrap();
+        let mac = MacAddress::new(&mac);
+
+        // the gateway acks every downlink
+        tokio::spawn(async move {
+            while let Ok(packet) = downlinks.recv().await {
+                if let Packet::Down(Down::PullResp(packet)) = packet {
+                    let ack = packet.into_ack_for_gateway(mac);
+                    uplinks.send(ack.into()).await.unwrap();
+                }
+            }
+        });
+
+        let connected = |event| matches!(event, server_runtime::Event::NewClient(_));
+        assert!(connected(upstream_a.recv().await));
+        assert!(connected(upstream_b.recv().await));
+
+        // each upstream only gets the TX_ACK of its own downlink
+        let timeout = Some(Duration::from_secs(2));
+        let (a, b) = tokio::join!(
+            upstream_a.send(txpk(), mac, timeout),
+            upstream_b.send(txpk(), mac, timeout)
+        );
+        assert!(a.is_ok() && b.is_ok());
+
+        // uplinks only reach the upstreams accepting them
+        uplinks_sender
+            .send(push_data::Packet::random().into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            upstream_a.recv().await,
+            server_runtime::Event::PacketReceived(_, gateway) if gateway == mac
+        ));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), upstream_b.recv())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+/*
+   Time on air of LoRa and FSK packets, following lgw_time_on_air of the
+   Semtech HAL:
+
+   LoRa: Tsym = 2^SF / BW
+         Npayload = 8 + max(ceil((8*PL - 4*SF + 28 + 16*CRC - 20*IH) / (4*(SF - 2*DE))) * (CR + 4), 0)
+         T = (Npreamble + 4.25 + Npayload) * Tsym
+   FSK:  T = 8 * (preamble + 3 byte sync word + 1 byte length + PL + 2*CRC) / bitrate
+*/
+use super::{Bandwidth, CodingRate, DataRate, SpreadingFactor};
+use std::time::Duration;
+
+// preamble lengths lora_pkt_fwd uses unless told otherwise
+const LORA_PREAMBLE_SYMBOLS: u64 = 8;
+const FSK_PREAMBLE_BYTES: u64 = 5;
+const FSK_SYNC_WORD_BYTES: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    /// symbols for LoRa, bytes for FSK; None for the lora_pkt_fwd default
+    pub preamble: Option<u64>,
+    /// LoRa only, false for implicit header mode
+    pub explicit_header: bool,
+    pub crc: bool,
+    /// LoRa only, None to enable it for symbols of 16 ms or more
+    pub low_datarate_optimize: Option<bool>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            preamble: None,
+            explicit_header: true,
+            crc: true,
+            low_datarate_optimize: None,
+        }
+    }
+}
+
+impl SpreadingFactor {
+    pub fn factor(&self) -> u32 {
+        match self {
+            SpreadingFactor::SF7 => 7,
+            SpreadingFactor::SF8 => 8,
+            SpreadingFactor::SF9 => 9,
+            SpreadingFactor::SF10 => 10,
+            SpreadingFactor::SF11 => 11,
+            SpreadingFactor::SF12 => 12,
+        }
+    }
+}
+
+impl Bandwidth {
+    pub fn hz(&self) -> u32 {
+        match self {
+            Bandwidth::BW125 => 125_000,
+            Bandwidth::BW250 => 250_000,
+            Bandwidth::BW500 => 500_000,
+        }
+    }
+}
+
+impl CodingRate {
+    // the CR of the formula, OFF is sent as 4/5 by the concentrator
+    fn cr(&self) -> i64 {
+        match self {
+            CodingRate::_4_5 | CodingRate::OFF => 1,
+            CodingRate::_4_6 => 2,
+            CodingRate::_4_7 => 3,
+            CodingRate::_4_8 => 4,
+        }
+    }
+}
+
+impl DataRate {
+    /// Duration of a single LoRa symbol
+    pub fn symbol_time(&self) -> Duration {
+        // a whole number of microseconds for every supported bandwidth
+        Duration::from_micros(
+            (1_000_000u64 << self.spreading_factor().factor()) / self.bandwidth().hz() as u64,
+        )
+    }
+
+    /// Time on air of a LoRa packet with a PHY payload of `len` bytes
+    pub fn time_on_air(&self, codr: &CodingRate, len: usize, options: &Options) -> Duration {
+        let sf = self.spreading_factor().factor() as i64;
+        let symbol = self.symbol_time();
+        let low_datarate_optimize = options
+            .low_datarate_optimize
+            .unwrap_or(symbol >= Duration::from_millis(16));
+        let preamble = options.preamble.unwrap_or(LORA_PREAMBLE_SYMBOLS);
+
+        let numerator = 8 * len as i64 - 4 * sf + 28 + if options.crc { 16 } else { 0 }
+            - if options.explicit_header { 0 } else { 20 };
+        let denominator = 4 * (sf - if low_datarate_optimize { 2 } else { 0 });
+        let payload_symbols =
+            8 + ((numerator as f64 / denominator as f64).ceil() as i64).max(0) * (codr.cr() + 4);
+
+        // counted in quarter symbols to keep the 4.25 symbols of sync word exact
+        let quarters = 4 * (preamble + payload_symbols as u64) + 17;
+        symbol * quarters as u32 / 4
+    }
+}
+
+/// Time on air of an FSK packet with a PHY payload of `len` bytes
+pub fn fsk_time_on_air(bitrate: u32, len: usize, options: &Options) -> Duration {
+    let bytes = options.preamble.unwrap_or(FSK_PREAMBLE_BYTES)
+        + FSK_SYNC_WORD_BYTES
+        + 1
+        + len as u64
+        + if options.crc { 2 } else { 0 };
+    Duration::from_nanos(8 * bytes * 1_000_000_000 / bitrate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lora_time_on_air() {
+        let sf7 = DataRate::new(SpreadingFactor::SF7, Bandwidth::BW125);
+        let sf9 = DataRate::new(SpreadingFactor::SF9, Bandwidth::BW125);
+        let sf12 = DataRate::new(SpreadingFactor::SF12, Bandwidth::BW125);
+        let options = Options::default();
+
+        // empty LoRaWAN uplink
+        assert_eq!(
+            sf7.time_on_air(&CodingRate::_4_5, 13, &options),
+            Duration::from_micros(46_336)
+        );
+        // low datarate optimization kicks in
+        assert_eq!(
+            sf12.time_on_air(&CodingRate::_4_5, 51, &options),
+            Duration::from_micros(2_465_792)
+        );
+        // downlinks are sent without CRC
+        let downlink = Options {
+            crc: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            sf9.time_on_air(&CodingRate::_4_5, 12, &downlink),
+            Duration::from_micros(144_384)
+        );
+        let implicit = Options {
+            explicit_header: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            sf7.time_on_air(&CodingRate::_4_5, 13, &implicit),
+            Duration::from_micros(41_216)
+        );
+    }
+
+    #[test]
+    fn test_fsk_time_on_air() {
+        assert_eq!(
+            fsk_time_on_air(50_000, 10, &Options::default()),
+            Duration::from_micros(3_360)
+        );
+    }
+}
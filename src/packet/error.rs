@@ -1,3 +1,6 @@
+use super::{parser, push_data::RxPk, Identifier, MacAddress};
+use serde_path_to_error::Segment;
+use std::net::SocketAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,20 +13,119 @@ pub enum Error {
     Parse(#[from] ParseError),
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum ParseError {
+    #[error("frame of {0} bytes is too short for its GWMP header")]
+    TooShort(usize),
     #[error("invalid GWMP version")]
     InvalidProtocolVersion,
     #[error("invalid GWMP frame identifier")]
     InvalidIdentifier,
+    #[error("unexpected {0:?} frame")]
+    UnexpectedIdentifier(Identifier),
     #[error("utf8 error")]
     Utf8(#[from] std::str::Utf8Error),
-    #[error("unable to parse GWMP JSON")]
-    Json,
+    #[error("unable to parse GWMP JSON: {0}")]
+    Json(#[from] JsonError),
+}
+
+/// Where and why serde rejected the JSON of a frame
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message} at {path} (line {line}, column {column})")]
+pub struct JsonError {
+    /// path to the offending field, eg: `rxpk[0].datr`
+    pub path: String,
+    /// serde's message, naming the offending value
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl JsonError {
+    fn at(path: &[Segment], e: &serde_json::error::Error) -> JsonError {
+        let mut message = e.to_string();
+        // serde_json appends the position, which has fields of its own
+        if let Some(at) = message.rfind(" at line ") {
+            message.truncate(at);
+        }
+        JsonError {
+            path: path_to_string(path),
+            message,
+            line: e.line(),
+            column: e.column(),
+        }
+    }
+
+    /// Error of the JSON of a frame. An rxpk goes through a
+    /// serde_json::Value to pick its version, which hides the path below it
+    /// from serde, so a failed one is deserialized again to find the rest
+    pub(crate) fn new(
+        json: &str,
+        e: serde_path_to_error::Error<serde_json::error::Error>,
+    ) -> JsonError {
+        let mut path: Vec<Segment> = e.path().iter().cloned().collect();
+        let mut error = JsonError::at(&path, e.inner());
+        if let [Segment::Map { key }, Segment::Seq { index }] = path.as_slice() {
+            if let Some(nested) = rxpk_error(json, key, *index) {
+                path.extend(nested.path().iter().cloned());
+                error.path = path_to_string(&path);
+                error.message = nested.into_inner().to_string();
+            }
+        }
+        error
+    }
+}
+
+fn rxpk_error(
+    json: &str,
+    key: &str,
+    index: usize,
+) -> Option<serde_path_to_error::Error<serde_json::error::Error>> {
+    if key != "rxpk" {
+        return None;
+    }
+    let mut value: serde_json::Value = serde_json::from_str(json).ok()?;
+    RxPk::from_value(value.get_mut(key)?.get_mut(index)?.take()).err()
+}
+
+// as serde_path_to_error::Path displays itself
+fn path_to_string(path: &[Segment]) -> String {
+    if path.is_empty() {
+        return ".".to_string();
+    }
+    let mut string = String::new();
+    for segment in path {
+        if !string.is_empty() && !matches!(segment, Segment::Seq { .. }) {
+            string.push('.');
+        }
+        string.push_str(&segment.to_string());
+    }
+    string
 }
 
 impl From<serde_json::error::Error> for ParseError {
-    fn from(_: serde_json::error::Error) -> ParseError {
-        ParseError::Json
+    fn from(e: serde_json::error::Error) -> ParseError {
+        JsonError::at(&[], &e).into()
+    }
+}
+
+/// Datagram which is no valid GWMP frame, with where it came from
+#[derive(Debug, Clone)]
+pub struct UnparsedFrame {
+    pub src: SocketAddr,
+    /// when the frame is an up one with a complete header
+    pub gateway_mac: Option<MacAddress>,
+    pub error: ParseError,
+    pub frame: Vec<u8>,
+}
+
+impl UnparsedFrame {
+    pub fn new(frame: &[u8], src: SocketAddr, error: ParseError) -> UnparsedFrame {
+        UnparsedFrame {
+            src,
+            gateway_mac: parser::up_gateway_mac(frame),
+            error,
+            frame: frame.to_vec(),
+        }
     }
 }
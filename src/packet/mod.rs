@@ -7,12 +7,12 @@ mod types;
 pub use types::*;
 
 mod error;
-pub use error::{Error, ParseError};
+pub use error::{Error, JsonError, ParseError, UnparsedFrame};
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
 const PROTOCOL_VERSION: u8 = 2;
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Identifier {
     PushData = 0,
@@ -23,12 +23,16 @@ pub enum Identifier {
     TxAck = 5,
 }
 
+pub mod airtime;
 pub mod pull_ack;
 pub mod pull_data;
 pub mod pull_resp;
 pub mod push_ack;
 pub mod push_data;
+pub mod time;
+pub use time::ConcentratorTimestamp;
 pub mod tx_ack;
+pub mod uplink;
 
 #[derive(Debug, Clone)]
 pub enum Packet {
@@ -115,7 +119,7 @@ fn write_preamble(w: &mut Cursor<&mut [u8]>, token: u16) -> Result {
     Ok(w.write_all(&[PROTOCOL_VERSION, (token >> 8) as u8, token as u8])?)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum StringOrNum {
     S(String),
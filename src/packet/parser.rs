@@ -5,6 +5,13 @@ use std::convert::TryFrom;
 const PROTOCOL_VERSION_INDEX: usize = 0;
 const IDENTIFIER_INDEX: usize = 3;
 const PACKET_PAYLOAD_START: usize = 8;
+const HEADER_LEN: usize = 4;
+
+// with the path to the field which failed
+fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> std::result::Result<T, ParseError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(json);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| JsonError::new(json, e).into())
+}
 
 fn random_token(buffer: &[u8]) -> u16 {
     (buffer[1] as u16) << 8 | buffer[2] as u16
@@ -14,12 +21,29 @@ pub fn gateway_mac(buffer: &[u8]) -> MacAddress {
     MacAddress::new(array_ref![buffer, 0, 8])
 }
 
+/// MAC of the gateway which sent an up frame, if its header is complete
+pub fn up_gateway_mac(frame: &[u8]) -> Option<MacAddress> {
+    let up = matches!(
+        frame
+            .get(IDENTIFIER_INDEX)
+            .map(|id| Identifier::try_from(*id)),
+        Some(Ok(Identifier::PushData
+            | Identifier::PullData
+            | Identifier::TxAck))
+    );
+    let header = frame.get(HEADER_LEN..HEADER_LEN + PACKET_PAYLOAD_START)?;
+    up.then(|| gateway_mac(header))
+}
+
 pub trait Parser {
     fn parse(buffer: &[u8]) -> std::result::Result<Packet, ParseError>;
 }
 
 impl Parser for Packet {
     fn parse(buffer: &[u8]) -> std::result::Result<Packet, ParseError> {
+        if buffer.len() < HEADER_LEN {
+            return Err(ParseError::TooShort(buffer.len()));
+        }
         if buffer[PROTOCOL_VERSION_INDEX] != PROTOCOL_VERSION {
             return Err(ParseError::InvalidProtocolVersion);
         };
@@ -28,7 +52,16 @@ impl Parser for Packet {
             Err(_) => Err(ParseError::InvalidIdentifier),
             Ok(id) => {
                 let random_token = random_token(buffer);
-                let buffer = &buffer[4..];
+                let buffer = &buffer[HEADER_LEN..];
+                let mac_len = match id {
+                    Identifier::PushData | Identifier::PullData | Identifier::TxAck => {
+                        PACKET_PAYLOAD_START
+                    }
+                    _ => 0,
+                };
+                if buffer.len() < mac_len {
+                    return Err(ParseError::TooShort(buffer.len() + HEADER_LEN));
+                }
                 Ok(match id {
                     // up packets
                     Identifier::PullData => {
@@ -42,7 +75,7 @@ impl Parser for Packet {
                     Identifier::PushData => {
                         let gateway_mac = gateway_mac(&buffer[..PACKET_PAYLOAD_START]);
                         let json_str = std::str::from_utf8(&buffer[PACKET_PAYLOAD_START..])?;
-                        let data = serde_json::from_str(json_str)?;
+                        let data = from_json(json_str)?;
 
                         push_data::Packet {
                             random_token,
@@ -55,7 +88,7 @@ impl Parser for Packet {
                         let gateway_mac = gateway_mac(&buffer[..PACKET_PAYLOAD_START]);
                         let data = if buffer.len() > PACKET_PAYLOAD_START {
                             let json_str = std::str::from_utf8(&buffer[PACKET_PAYLOAD_START..])?;
-                            serde_json::from_str(json_str)?
+                            from_json(json_str)?
                         } else {
                             TxPkNack::default()
                         };
@@ -71,7 +104,7 @@ impl Parser for Packet {
                     Identifier::PullAck => pull_ack::Packet { random_token }.into(),
                     Identifier::PullResp => {
                         let json_str = std::str::from_utf8(buffer)?;
-                        let data = serde_json::from_str(json_str)?;
+                        let data = from_json(json_str)?;
                         pull_resp::Packet { random_token, data }.into()
                     }
                 })
@@ -11,11 +11,12 @@ Bytes  | Function
 4-end  | JSON object, starting with {, ending with }, see section 6
  */
 use super::{
-    tx_ack, write_preamble, CodingRate, DataRate, Error as PktError, Identifier, MacAddress,
-    Modulation, SerializablePacket, StringOrNum,
+    airtime, time::GpsTime, tx_ack, write_preamble, CodingRate, ConcentratorTimestamp, DataRate,
+    Error as PktError, Identifier, MacAddress, Modulation, SerializablePacket, StringOrNum,
 };
 use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Write};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Packet {
@@ -85,14 +86,17 @@ impl Data {
  size | number | RF packet payload size in bytes (unsigned integer)
  data | string | Base64 encoded RF packet payload, padding optional
  ncrc | bool   | If true, disable the CRC of the physical layer (optional)
+ nhdr | bool   | If true, disable the header of the physical layer (optional)
  */
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxPk {
-    pub imme: bool,        // Send packet immediately (will ignore tmst & time)
-    pub tmst: StringOrNum, // Send packet on a certain timestamp value (will ignore time)
+    pub imme: bool, // Send packet immediately (will ignore tmst & time)
+    // absent when sent on GPS time, lora_pkt_fwd would otherwise ignore tmms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmst: Option<StringOrNum>, // Send packet on a certain timestamp value (will ignore time)
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tmms: Option<StringOrNum>, // Send packet at a certain GPS time (GPS synchronization required)
+    pub tmms: Option<u64>, // Send packet at a certain GPS time (GPS synchronization required)
     pub freq: f64,        // TX central frequency in MHz (unsigned float, Hz precision)
     pub rfch: u64,        // Concentrator "RF chain" used for TX (unsigned integer)
     pub powe: u64,        // TX output power in dBm (unsigned integer, dBm precision)
@@ -108,6 +112,11 @@ pub struct TxPk {
     pub data: Vec<u8>, // Data to be transmitted, as bytes
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ncrc: Option<bool>, // If true, disable the CRC of the physical layer (optional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nhdr: Option<bool>, // If true, disable the header of the physical layer (optional)
+    // fields this crate does not model, eg: for another packet forwarder
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 use std::fmt;
@@ -116,7 +125,7 @@ impl fmt::Display for TxPk {
         write!(
             f,
             "{}, {:.2} MHz, {:?}, len: {}",
-            if let StringOrNum::N(time) = self.tmst {
+            if let Some(StringOrNum::N(time)) = self.tmst {
                 format!("@{} us", time)
             } else {
                 "immediately".into()
@@ -124,7 +133,61 @@ impl fmt::Display for TxPk {
             self.freq,
             self.datr,
             self.size
-        )
+        )?;
+        #[cfg(feature = "lorawan")]
+        if let Ok(phy_payload) = self.get_phy_payload() {
+            write!(f, ", {}", phy_payload)?;
+        }
+        Ok(())
+    }
+}
+
+impl TxPk {
+    /// None when the packet is sent immediately or on GPS time
+    pub fn get_timestamp(&self) -> Option<ConcentratorTimestamp> {
+        match (self.imme, &self.tmst) {
+            (false, Some(StringOrNum::N(tmst))) => Some((*tmst).into()),
+            _ => None,
+        }
+    }
+
+    /// None unless the packet is sent on GPS time
+    pub fn get_gps_time(&self) -> Option<GpsTime> {
+        match (self.imme, self.get_timestamp(), self.tmms) {
+            (false, None, Some(tmms)) => Some(GpsTime::from_millis(tmms)),
+            _ => None,
+        }
+    }
+
+    /// Schedules the packet on GPS time, for gateways with a GPS lock
+    pub fn set_gps_time(&mut self, tmms: GpsTime) {
+        self.imme = false;
+        self.tmst = None;
+        self.tmms = Some(tmms.as_millis());
+    }
+
+    /// Schedules the packet on the concentrator counter
+    pub fn set_timestamp(&mut self, tmst: ConcentratorTimestamp) {
+        self.imme = false;
+        self.tmst = Some(tmst.into());
+        self.tmms = None;
+    }
+
+    pub fn time_on_air(&self) -> Duration {
+        let options = airtime::Options {
+            preamble: self.prea,
+            explicit_header: !self.nhdr.unwrap_or(false),
+            crc: !self.ncrc.unwrap_or(false),
+            ..Default::default()
+        };
+        self.datr.time_on_air(&self.codr, self.data.len(), &options)
+    }
+
+    #[cfg(feature = "lorawan")]
+    pub fn get_phy_payload(
+        &self,
+    ) -> std::result::Result<crate::lorawan::PhyPayload<'_>, crate::lorawan::Error> {
+        crate::lorawan::PhyPayload::new(&self.data)
     }
 }
 
@@ -13,12 +13,15 @@ Bytes  | Function
 12-end | JSON object, starting with {, ending with }, see section 4
  */
 use super::{
-    push_ack, write_preamble, CodingRate, DataRate, Error as PktError, Identifier, MacAddress,
-    Modulation, SerializablePacket,
+    airtime, push_ack,
+    time::{self, GpsTime},
+    write_preamble, CodingRate, ConcentratorTimestamp, DataRate, Error as PktError, Identifier,
+    MacAddress, Modulation, SerializablePacket,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::io::{Cursor, Write};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone)]
 pub struct Packet {
@@ -28,6 +31,18 @@ pub struct Packet {
 }
 
 impl Packet {
+    pub fn from_stat(stat: Stat) -> Packet {
+        Packet {
+            random_token: 0,
+            gateway_mac: MacAddress { bytes: [0; 8] },
+            data: Data {
+                rxpk: None,
+                stat: Some(Box::new(stat)),
+                extra: serde_json::Map::new(),
+            },
+        }
+    }
+
     pub fn from_rxpk(rxpk: RxPk) -> Packet {
         let rxpk = vec![rxpk];
         Packet {
@@ -36,6 +51,7 @@ impl Packet {
             data: Data {
                 rxpk: Some(rxpk),
                 stat: None,
+                extra: serde_json::Map::new(),
             },
         }
     }
@@ -55,6 +71,7 @@ impl Packet {
             size: 12,
             stat: CRC::OK,
             tmst: 12,
+            extra: serde_json::Map::new(),
         })];
 
         Packet {
@@ -63,6 +80,7 @@ impl Packet {
             data: Data {
                 rxpk: Some(rxpk),
                 stat: None,
+                extra: serde_json::Map::new(),
             },
         }
     }
@@ -72,8 +90,13 @@ impl Packet {
 pub struct Data {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rxpk: Option<Vec<RxPk>>,
+    // boxed as it makes up most of the frame but only comes every stat
+    // interval, which keeps Up and the channels carrying it small
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub stat: Option<Stat>,
+    pub stat: Option<Box<Stat>>,
+    // fields this crate does not model, kept so that relaying a frame is lossless
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /*
@@ -112,6 +135,28 @@ pub struct RxPkV1 {
     pub size: u64,
     pub stat: CRC,
     pub tmst: u32,
+    // vendor specific fields, eg: meta
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RxPkV1 {
+    // the reception of the frame, as it would be reported in version 2
+    pub(crate) fn rsig(&self) -> RSig {
+        RSig {
+            ant: 0,
+            chan: self.chan,
+            rssic: self.rssi,
+            rssis: self.rssis,
+            lsnr: self.lsnr,
+            etime: None,
+            foff: None,
+            ftstat: None,
+            ftver: None,
+            ftdelta: None,
+            extra: serde_json::Map::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize_repr, Deserialize_repr, Clone, PartialEq)]
@@ -162,6 +207,9 @@ pub struct RxPkV2 {
     pub delayed: Option<bool>,
     pub tmms: Option<u64>,
     pub time: Option<String>,
+    // vendor specific fields, eg: meta
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /*
@@ -192,15 +240,43 @@ pub struct RSig {
     pub ftstat: Option<u8>,
     pub ftver: Option<usize>,
     pub ftdelta: Option<isize>,
+    // vendor specific fields of the antenna
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Either version of rxpk, see `uplink::Uplink` for a view of both
+#[derive(Serialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum RxPk {
     V1(RxPkV1),
     V2(RxPkV2),
 }
 
+impl<'de> Deserialize<'de> for RxPk {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<RxPk, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        // the path below the rxpk is lost here, JsonError::new finds it again
+        RxPk::from_value(value).map_err(|e| de::Error::custom(e.into_inner()))
+    }
+}
+
+impl RxPk {
+    // only version 2 frames carry a jver, so that errors name the missing field
+    pub(crate) fn from_value(
+        value: serde_json::Value,
+    ) -> std::result::Result<RxPk, serde_path_to_error::Error<serde_json::error::Error>> {
+        if value.get("jver").is_some() {
+            serde_path_to_error::deserialize(value).map(RxPk::V2)
+        } else {
+            serde_path_to_error::deserialize(value).map(RxPk::V1)
+        }
+    }
+}
+
 use std::fmt;
 impl fmt::Display for RxPk {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -217,7 +293,12 @@ impl fmt::Display for RxPk {
             },
             self.get_snr(),
             self.get_data().len()
-        )
+        )?;
+        #[cfg(feature = "lorawan")]
+        if let Ok(phy_payload) = self.get_phy_payload() {
+            write!(f, ", {}", phy_payload)?;
+        }
+        Ok(())
     }
 }
 
@@ -229,50 +310,85 @@ macro_rules! get_field {
         }
     };
 }
-use std::cmp;
+
+/// How the receptions of an uplink heard by several antennas add up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// values of the best reception, see `RxPk::best_reception`
+    Best,
+    /// diversity estimate, as maximum ratio combining: the linear SNRs and
+    /// powers of the antennas add up
+    Combined,
+}
+
+/// Reception with the highest SNR, then RSSI
+pub(crate) fn best_rsig(rsig: &[RSig]) -> Option<&RSig> {
+    rsig.iter().reduce(|best, rsig| {
+        if (rsig.lsnr, rsig.rssic) > (best.lsnr, best.rssic) {
+            rsig
+        } else {
+            best
+        }
+    })
+}
+
+// in dB, of values in dB
+fn combine(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let sum = values
+        .map(|db| 10f32.powf(db / 10.0))
+        .reduce(|a, b| a + b)?;
+    Some(10.0 * sum.log10())
+}
 
 impl RxPk {
+    /// SNR of the best reception, -150 dB when no antenna reports any
     pub fn get_snr(&self) -> f32 {
-        match self {
-            RxPk::V1(pk) => pk.lsnr,
-            RxPk::V2(pk) => pk
-                .rsig
-                .iter()
-                // truncate the decimal when choosing best LSNR value
-                .fold(-150.0, |max, x| {
-                    if (max as u32) < (x.lsnr as u32) {
-                        x.lsnr
-                    } else {
-                        max
-                    }
-                }),
+        self.snr(Aggregation::Best).unwrap_or(-150.0)
+    }
+
+    /// None when no antenna reports the reception
+    pub fn snr(&self, aggregation: Aggregation) -> Option<f32> {
+        let receptions = self.receptions();
+        match aggregation {
+            Aggregation::Best => best_rsig(&receptions).map(|rsig| rsig.lsnr),
+            Aggregation::Combined => combine(receptions.iter().map(|rsig| rsig.lsnr)),
         }
     }
 
-    pub fn get_channel_rssi(&self) -> i32 {
-        match self {
-            RxPk::V1(pk) => pk.rssi,
-            RxPk::V2(pk) => pk.rsig.iter().fold(-150, |max, x| cmp::max(max, x.rssic)),
+    /// Channel RSSI in dBm, rounded down when combined
+    pub fn rssi(&self, aggregation: Aggregation) -> Option<i32> {
+        let receptions = self.receptions();
+        match aggregation {
+            Aggregation::Best => best_rsig(&receptions).map(|rsig| rsig.rssic),
+            Aggregation::Combined => combine(receptions.iter().map(|rsig| rsig.rssic as f32))
+                .map(|rssi| rssi.floor() as i32),
         }
     }
 
-    pub fn get_signal_rssi(&self) -> Option<i32> {
+    /// Reception of every antenna, the only one of a version 1 frame on
+    /// antenna 0
+    pub fn receptions(&self) -> Vec<RSig> {
         match self {
-            RxPk::V1(pk) => pk.rssis,
-            RxPk::V2(pk) => pk.rsig.iter().fold(None, |max, x| {
-                if let Some(rssis) = x.rssis {
-                    Some(if let Some(current_max) = max {
-                        cmp::max(current_max, rssis)
-                    } else {
-                        rssis
-                    })
-                } else {
-                    max
-                }
-            }),
+            RxPk::V1(pk) => vec![pk.rsig()],
+            RxPk::V2(pk) => pk.rsig.clone(),
         }
     }
 
+    /// Antenna and IF channel which heard the uplink best, with its figures
+    pub fn best_reception(&self) -> Option<RSig> {
+        best_rsig(&self.receptions()).cloned()
+    }
+
+    /// Channel RSSI of the best reception
+    pub fn get_channel_rssi(&self) -> i32 {
+        self.best_reception().map_or(-150, |rsig| rsig.rssic)
+    }
+
+    /// Signal RSSI of the best reception, if its antenna reports one
+    pub fn get_signal_rssi(&self) -> Option<i32> {
+        self.best_reception().and_then(|rsig| rsig.rssis)
+    }
+
     pub fn get_frequency(&self) -> &f64 {
         get_field!(self, freq)
     }
@@ -281,8 +397,8 @@ impl RxPk {
         get_field!(self, data)
     }
 
-    pub fn get_timestamp(&self) -> &u32 {
-        get_field!(self, tmst)
+    pub fn get_timestamp(&self) -> ConcentratorTimestamp {
+        (*get_field!(self, tmst)).into()
     }
 
     pub fn get_datarate(&self) -> DataRate {
@@ -292,6 +408,39 @@ impl RxPk {
     pub fn get_crc_status(&self) -> &CRC {
         get_field!(self, stat)
     }
+
+    /// UTC time of reception, None when the gateway sent none or a format
+    /// even lenient parsing does not understand
+    pub fn get_time(&self) -> Option<SystemTime> {
+        match self {
+            RxPk::V1(_) => None,
+            RxPk::V2(pk) => time::parse_utc(pk.time.as_ref()?, time::Parsing::Lenient).ok(),
+        }
+    }
+
+    /// GPS time of reception, only sent by gateways with a GPS lock
+    pub fn get_gps_time(&self) -> Option<GpsTime> {
+        match self {
+            RxPk::V1(_) => None,
+            RxPk::V2(pk) => pk.tmms.map(GpsTime::from_millis),
+        }
+    }
+
+    /// Assumes the explicit header, CRC and 8 symbol preamble of LoRaWAN uplinks
+    pub fn time_on_air(&self) -> Duration {
+        self.get_datarate().time_on_air(
+            get_field!(self, codr),
+            self.get_data().len(),
+            &airtime::Options::default(),
+        )
+    }
+
+    #[cfg(feature = "lorawan")]
+    pub fn get_phy_payload(
+        &self,
+    ) -> std::result::Result<crate::lorawan::PhyPayload<'_>, crate::lorawan::Error> {
+        crate::lorawan::PhyPayload::new(self.get_data())
+    }
 }
 
 /*
@@ -312,20 +461,30 @@ txnb | number | Number of packets emitted (unsigned integer)
 // the order of this is important as it makes us identical to Semtech
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Stat {
-    time: String,
+    pub time: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    lati: Option<f64>,
+    pub lati: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    long: Option<f64>,
+    pub long: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    alti: Option<u64>,
-    rxnb: u64,
-    rxok: u64,
-    rxfw: u64,
+    pub alti: Option<u64>,
+    pub rxnb: u64,
+    pub rxok: u64,
+    pub rxfw: u64,
     // if there were no upstream datagrams, this field can be null
-    ackr: Option<f64>,
-    dwnb: u64,
-    txnb: u64,
+    pub ackr: Option<f64>,
+    pub dwnb: u64,
+    pub txnb: u64,
+    // custom fields, eg: vendor specific counters, are appended after the standard ones
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Stat {
+    /// System time of the gateway, None when even lenient parsing fails
+    pub fn get_time(&self) -> Option<SystemTime> {
+        time::parse_utc(&self.time, time::Parsing::Lenient).ok()
+    }
 }
 
 impl SerializablePacket for Packet {
@@ -0,0 +1,485 @@
+/*
+   Time fields of the protocol: the UTC time found in rxpk and stat JSON
+   objects, the GPS time of tmms and the tmst counter of the concentrator.
+
+   tmst counts microseconds on 32 bits and wraps around every ~71 minutes,
+   so two timestamps are only ordered relative to each other when they are
+   less than half a wrap (~35 minutes) apart.
+
+   tmms counts milliseconds since the GPS epoch (06.Jan.1980) without leap
+   seconds, so it runs ahead of UTC by the leap seconds inserted since then.
+
+   UTC times are formatted as ISO 8601 'compact' in rxpk and 'expanded' in
+   stat. Strict parsing only accepts these two formats, lenient parsing
+   also accepts what other packet forwarders send: any fraction of a second
+   or none, 'T' or space as separator, and a Z, GMT, UTC or numeric offset
+   suffix, or none at all.
+*/
+use super::StringOrNum;
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("invalid UTC time {0:?}")]
+    InvalidFormat(String),
+    #[error("UTC time {0:?} is before the UNIX epoch")]
+    OutOfRange(String),
+}
+
+/// Value of the free running microsecond counter of the concentrator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ConcentratorTimestamp(u32);
+
+impl ConcentratorTimestamp {
+    pub fn new(tmst: u32) -> ConcentratorTimestamp {
+        ConcentratorTimestamp(tmst)
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+
+    /// Microseconds from `earlier` to self, negative when self comes first
+    pub fn signed_duration_since(&self, earlier: ConcentratorTimestamp) -> i64 {
+        self.0.wrapping_sub(earlier.0) as i32 as i64
+    }
+
+    /// Whether self comes strictly before `other`. The counter wraps, so
+    /// this only holds for timestamps less than half a wrap (~35 minutes)
+    /// apart: of two timestamps further apart, the later one is taken for
+    /// the earlier. Not an ordering, since it is not transitive across a
+    /// whole wrap
+    pub fn is_before(&self, other: ConcentratorTimestamp) -> bool {
+        other.signed_duration_since(*self) > 0
+    }
+
+    /// Time elapsed since `earlier`, zero when self comes first
+    pub fn duration_since(&self, earlier: ConcentratorTimestamp) -> Duration {
+        Duration::from_micros(self.signed_duration_since(earlier).max(0) as u64)
+    }
+}
+
+impl From<u32> for ConcentratorTimestamp {
+    fn from(tmst: u32) -> ConcentratorTimestamp {
+        ConcentratorTimestamp(tmst)
+    }
+}
+
+impl From<ConcentratorTimestamp> for u32 {
+    fn from(tmst: ConcentratorTimestamp) -> u32 {
+        tmst.0
+    }
+}
+
+impl From<ConcentratorTimestamp> for StringOrNum {
+    fn from(tmst: ConcentratorTimestamp) -> StringOrNum {
+        StringOrNum::N(tmst.0)
+    }
+}
+
+// durations are truncated to the counter, a whole wrap adds nothing
+impl Add<Duration> for ConcentratorTimestamp {
+    type Output = ConcentratorTimestamp;
+    fn add(self, duration: Duration) -> ConcentratorTimestamp {
+        ConcentratorTimestamp(self.0.wrapping_add(duration.as_micros() as u32))
+    }
+}
+
+impl Sub<Duration> for ConcentratorTimestamp {
+    type Output = ConcentratorTimestamp;
+    fn sub(self, duration: Duration) -> ConcentratorTimestamp {
+        ConcentratorTimestamp(self.0.wrapping_sub(duration.as_micros() as u32))
+    }
+}
+
+impl fmt::Display for ConcentratorTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// converts a unix timestamp into (year, month, day, hour, minute, second, microsecond)
+fn civil(time: SystemTime) -> (i64, u32, u32, u64, u64, u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's days_from_civil inverse
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_micros(),
+    )
+}
+
+// days since the UNIX epoch of a date, inverse of the computation in civil
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// number of days of a month, 29 for February of leap years
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 => (days_from_civil(year, 3, 1) - days_from_civil(year, 2, 1)) as u32,
+        _ => 31,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parsing {
+    /// only the 'compact' and 'expanded' formats of the protocol
+    Strict,
+    /// the variants sent by other packet forwarders as well
+    Lenient,
+}
+
+/// Parses the UTC time of rxpk or stat
+pub fn parse_utc(time: &str, parsing: Parsing) -> Result<SystemTime, Error> {
+    let invalid = || Error::InvalidFormat(time.into());
+    let num = |range: std::ops::Range<usize>| -> Result<u32, Error> {
+        match time.get(range) {
+            Some(digits) if digits.bytes().all(|c| c.is_ascii_digit()) => {
+                digits.parse().map_err(|_| invalid())
+            }
+            _ => Err(invalid()),
+        }
+    };
+    let bytes = time.as_bytes();
+    if bytes.len() < 19 || [bytes[4], bytes[7], bytes[13], bytes[16]] != *b"--::" {
+        return Err(invalid());
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year as i64, month)).contains(&day)
+        || hour > 23
+        || min > 59
+        || sec > 59
+    {
+        return Err(invalid());
+    }
+
+    let mut rest = &time[19..];
+    let mut fraction_digits = 0;
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        fraction_digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if fraction_digits == 0 || fraction_digits > 9 {
+            return Err(invalid());
+        }
+        nanos = num(20..20 + fraction_digits)? * 10u32.pow(9 - fraction_digits as u32);
+        rest = &fraction[fraction_digits..];
+    }
+
+    let offset_secs = match (parsing, bytes[10], rest) {
+        (Parsing::Strict, b'T', "Z") if fraction_digits == 6 => 0,
+        (Parsing::Strict, b' ', " GMT") if fraction_digits == 0 => 0,
+        (Parsing::Strict, _, _) => return Err(invalid()),
+        (Parsing::Lenient, b'T' | b't' | b' ', suffix) => match suffix.trim_start() {
+            "" | "Z" | "z" | "GMT" | "UTC" => 0,
+            offset => {
+                let sign = match offset.as_bytes()[0] {
+                    b'+' => 1,
+                    b'-' => -1,
+                    _ => return Err(invalid()),
+                };
+                let digits = offset[1..].replace(':', "");
+                if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|c| c.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let hours: i64 = digits[..2].parse().map_err(|_| invalid())?;
+                let minutes: i64 = digits.get(2..).unwrap_or("0").parse().unwrap_or(0);
+                sign * (hours * 3600 + minutes * 60)
+            }
+        },
+        (Parsing::Lenient, _, _) => return Err(invalid()),
+    };
+
+    let secs = days_from_civil(year as i64, month, day) * 86400
+        + (hour * 3600 + min * 60 + sec) as i64
+        - offset_secs;
+    if secs < 0 {
+        return Err(Error::OutOfRange(time.into()));
+    }
+    Ok(UNIX_EPOCH + Duration::new(secs as u64, nanos))
+}
+
+// GPS epoch (06.Jan.1980) as seconds since the UNIX epoch
+const GPS_EPOCH_UNIX_SECS: u64 = 315_964_800;
+
+// first day of the month from which GPS time is ahead of UTC by the offset
+const LEAP_SECONDS: [(i64, u32, u32); 18] = [
+    (1981, 7, 1),
+    (1982, 7, 2),
+    (1983, 7, 3),
+    (1985, 7, 4),
+    (1988, 1, 5),
+    (1990, 1, 6),
+    (1991, 1, 7),
+    (1992, 7, 8),
+    (1993, 7, 9),
+    (1994, 7, 10),
+    (1996, 1, 11),
+    (1997, 7, 12),
+    (1999, 1, 13),
+    (2006, 1, 14),
+    (2009, 1, 15),
+    (2012, 7, 16),
+    (2015, 7, 17),
+    (2017, 1, 18),
+];
+
+/// Offsets between GPS time and UTC, defaults to the leap seconds announced
+/// up to 2017; insert new ones as the IERS announces them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeapSeconds {
+    // UNIX time in seconds from which the offset applies, sorted
+    table: Vec<(u64, u32)>,
+}
+
+impl Default for LeapSeconds {
+    fn default() -> LeapSeconds {
+        LeapSeconds {
+            table: LEAP_SECONDS
+                .iter()
+                .map(|(year, month, offset)| {
+                    (days_from_civil(*year, *month, 1) as u64 * 86400, *offset)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LeapSeconds {
+    /// A table without any leap second, GPS time then only differs from UTC
+    /// by its epoch
+    pub fn none() -> LeapSeconds {
+        LeapSeconds { table: Vec::new() }
+    }
+
+    /// GPS time is `offset` seconds ahead of UTC from `utc` on
+    pub fn insert(&mut self, utc: SystemTime, offset: u32) {
+        let secs = utc.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let index = self.table.partition_point(|(from, _)| *from < secs);
+        self.table.insert(index, (secs, offset));
+    }
+
+    /// Seconds GPS time is ahead of UTC at this UTC time
+    pub fn offset_at(&self, utc: SystemTime) -> u32 {
+        let secs = utc.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.offset_where(|from, _| from <= secs)
+    }
+
+    // offset of the last entry taking effect before the time
+    fn offset_where(&self, effective: impl Fn(u64, u32) -> bool) -> u32 {
+        self.table
+            .iter()
+            .rev()
+            .find(|(from, offset)| effective(*from, *offset))
+            .map_or(0, |(_, offset)| *offset)
+    }
+}
+
+/// GPS time of tmms: milliseconds since the GPS epoch, without leap seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GpsTime(u64);
+
+impl GpsTime {
+    pub fn from_millis(tmms: u64) -> GpsTime {
+        GpsTime(tmms)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// None for times before the GPS epoch
+    pub fn from_utc(utc: SystemTime, leap_seconds: &LeapSeconds) -> Option<GpsTime> {
+        let unix = utc.duration_since(UNIX_EPOCH).ok()?;
+        let since_epoch = unix.checked_sub(Duration::from_secs(GPS_EPOCH_UNIX_SECS))?;
+        let offset = leap_seconds.offset_at(utc) as u64;
+        Some(GpsTime(since_epoch.as_millis() as u64 + offset * 1_000))
+    }
+
+    pub fn to_utc(&self, leap_seconds: &LeapSeconds) -> SystemTime {
+        let gps_secs = self.0 / 1_000 + GPS_EPOCH_UNIX_SECS;
+        // leap seconds take effect on the GPS clock offset seconds later
+        let offset = leap_seconds.offset_where(|from, offset| from + offset as u64 <= gps_secs);
+        UNIX_EPOCH + Duration::from_millis(self.0 + GPS_EPOCH_UNIX_SECS * 1_000)
+            - Duration::from_secs(offset as u64)
+    }
+}
+
+impl fmt::Display for GpsTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ms", self.0)
+    }
+}
+
+/// Formats in the ISO 8601 'compact' format of rxpk, eg: 2020-10-29T15:57:40.170301Z
+pub fn utc_compact(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec, us) = civil(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year, month, day, hour, min, sec, us
+    )
+}
+
+/// Formats in the ISO 8601 'expanded' format of stat, eg: 2020-03-04 07:01:02 GMT
+pub fn utc_expanded(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec, _) = civil(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} GMT",
+        year, month, day, hour, min, sec
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utc_formats() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_603_987_060_170_301);
+        assert_eq!(utc_compact(time), "2020-10-29T15:57:40.170301Z");
+        assert_eq!(utc_expanded(time), "2020-10-29 15:57:40 GMT");
+    }
+
+    #[test]
+    fn test_parse_utc() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_603_987_060_170_301);
+        let seconds = UNIX_EPOCH + Duration::from_secs(1_603_987_060);
+        for parsing in [Parsing::Strict, Parsing::Lenient] {
+            assert_eq!(parse_utc(&utc_compact(time), parsing), Ok(time));
+            assert_eq!(parse_utc(&utc_expanded(time), parsing), Ok(seconds));
+        }
+
+        for lenient in [
+            "2020-10-29T15:57:40Z",
+            "2020-10-29 15:57:40",
+            "2020-10-29T15:57:40.000 UTC",
+            "2020-10-29T16:57:40+01:00",
+            "2020-10-29T14:57:40-0100",
+        ] {
+            assert_eq!(parse_utc(lenient, Parsing::Lenient), Ok(seconds));
+            assert!(parse_utc(lenient, Parsing::Strict).is_err());
+        }
+        assert_eq!(
+            parse_utc("2020-10-29T15:57:40.1234567890Z", Parsing::Lenient),
+            Err(Error::InvalidFormat(
+                "2020-10-29T15:57:40.1234567890Z".into()
+            ))
+        );
+        assert!(parse_utc("2020-13-29T15:57:40Z", Parsing::Lenient).is_err());
+
+        // days past the end of the month, leap years have a February 29
+        for parsing in [Parsing::Strict, Parsing::Lenient] {
+            for invalid in [
+                "2020-02-30T00:00:00.000000Z",
+                "2021-02-29T00:00:00.000000Z",
+                "2100-02-29T00:00:00.000000Z",
+                "2021-04-31T00:00:00.000000Z",
+                "2021-11-31T00:00:00.000000Z",
+                "2021-12-32T00:00:00.000000Z",
+            ] {
+                assert!(parse_utc(invalid, parsing).is_err(), "{}", invalid);
+            }
+            for valid in [
+                "2020-02-29T00:00:00.000000Z",
+                "2000-02-29T00:00:00.000000Z",
+                "2021-04-30T00:00:00.000000Z",
+                "2021-12-31T00:00:00.000000Z",
+            ] {
+                assert!(parse_utc(valid, parsing).is_ok(), "{}", valid);
+            }
+        }
+        assert_eq!(
+            parse_utc("2020-02-29T00:00:00.000000Z", Parsing::Strict),
+            parse_utc("2020-03-01T00:00:00.000000Z", Parsing::Strict)
+                .map(|time| time - Duration::from_secs(86400))
+        );
+        assert!(parse_utc("1969-12-31T23:59:59Z", Parsing::Lenient).is_err());
+    }
+
+    #[test]
+    fn test_gps_time() {
+        let leap_seconds = LeapSeconds::default();
+        let utc = parse_utc("2020-10-29T15:57:40.170000Z", Parsing::Strict).unwrap();
+        let gps = GpsTime::from_utc(utc, &leap_seconds).unwrap();
+        // 18 leap seconds ahead of UTC
+        assert_eq!(gps.as_millis(), 1_288_022_278_170);
+        assert_eq!(gps.to_utc(&leap_seconds), utc);
+
+        // before any leap second, only the epochs differ
+        let epoch = UNIX_EPOCH + Duration::from_secs(GPS_EPOCH_UNIX_SECS);
+        assert_eq!(GpsTime::from_utc(epoch, &leap_seconds), Some(GpsTime(0)));
+
+        let mut announced = LeapSeconds::default();
+        let next = parse_utc("2020-07-01T00:00:00Z", Parsing::Lenient).unwrap();
+        announced.insert(next, 19);
+        assert_eq!(announced.offset_at(utc), 19);
+        assert_eq!(
+            GpsTime::from_utc(utc, &announced).unwrap().as_millis(),
+            gps.as_millis() + 1_000
+        );
+        assert_eq!(
+            GpsTime::from_utc(utc, &LeapSeconds::none())
+                .unwrap()
+                .as_millis(),
+            1_288_022_260_170
+        );
+    }
+
+    #[test]
+    fn test_concentrator_timestamp() {
+        let before_wrap = ConcentratorTimestamp::new(u32::MAX - 99);
+        let after_wrap = before_wrap + Duration::from_micros(300);
+        assert_eq!(after_wrap.as_u32(), 200);
+        assert_eq!(after_wrap - Duration::from_micros(300), before_wrap);
+        assert!(before_wrap.is_before(after_wrap));
+        assert!(!after_wrap.is_before(before_wrap));
+        assert!(!after_wrap.is_before(after_wrap));
+        assert_eq!(after_wrap.signed_duration_since(before_wrap), 300);
+        assert_eq!(before_wrap.signed_duration_since(after_wrap), -300);
+        assert_eq!(
+            after_wrap.duration_since(before_wrap),
+            Duration::from_micros(300)
+        );
+        assert_eq!(before_wrap.duration_since(after_wrap), Duration::ZERO);
+
+        // exactly half a wrap apart, neither comes first
+        let opposite = ConcentratorTimestamp::new(200 + (1 << 31));
+        assert!(!after_wrap.is_before(opposite));
+        assert!(!opposite.is_before(after_wrap));
+
+        // not transitive: three steps of a third of a wrap come back around
+        let third = Duration::from_micros(1 << 32) / 3;
+        let (a, b) = (after_wrap, after_wrap + third);
+        let c = b + third;
+        assert!(a.is_before(b) && b.is_before(c) && c.is_before(a));
+    }
+}
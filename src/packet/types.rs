@@ -5,8 +5,8 @@ pub use data_rate::*;
 pub mod data_rate {
     use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
     use std::cmp::PartialEq;
+    use std::fmt;
     use std::str::FromStr;
-    use std::string::ToString;
     #[derive(Debug, Clone, Default, PartialEq)]
     pub struct DataRate(SpreadingFactor, Bandwidth);
 
@@ -38,11 +38,9 @@ pub mod data_rate {
         }
     }
 
-    impl ToString for DataRate {
-        fn to_string(&self) -> String {
-            let mut output = self.spreading_factor().to_string();
-            output.push_str(&self.bandwidth().to_string());
-            output
+    impl fmt::Display for DataRate {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}{}", self.spreading_factor(), self.bandwidth())
         }
     }
 
@@ -61,13 +59,14 @@ pub mod data_rate {
         where
             D: Deserializer<'de>,
         {
-            let s = <&str>::deserialize(deserializer)?;
-            DataRate::from_str(s).map_err(de::Error::custom)
+            let s = String::deserialize(deserializer)?;
+            DataRate::from_str(&s).map_err(de::Error::custom)
         }
     }
 
-    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
     pub enum SpreadingFactor {
+        #[default]
         SF7,
         SF8,
         SF9,
@@ -91,15 +90,10 @@ pub mod data_rate {
         }
     }
 
-    impl Default for SpreadingFactor {
-        fn default() -> Self {
-            SpreadingFactor::SF7
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
     pub enum Bandwidth {
         BW125,
+        #[default]
         BW250,
         BW500,
     }
@@ -116,21 +110,15 @@ pub mod data_rate {
         }
     }
 
-    impl ToString for Bandwidth {
-        fn to_string(&self) -> String {
-            format!("{:?}", self)
-        }
-    }
-
-    impl ToString for SpreadingFactor {
-        fn to_string(&self) -> String {
-            format!("{:?}", self)
+    impl fmt::Display for Bandwidth {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{:?}", self)
         }
     }
 
-    impl Default for Bandwidth {
-        fn default() -> Self {
-            Bandwidth::BW250
+    impl fmt::Display for SpreadingFactor {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{:?}", self)
         }
     }
 
@@ -210,7 +198,8 @@ pub(crate) mod base64 {
     where
         D: Deserializer<'de>,
     {
-        let s = <&str>::deserialize(deserializer)?;
+        // owned, as buffered JSON, eg: of an untagged enum or a flattened map, is
+        let s = String::deserialize(deserializer)?;
         base64::decode(s).map_err(de::Error::custom)
     }
 }
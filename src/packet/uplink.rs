@@ -0,0 +1,270 @@
+/*
+   Version independent view of an rxpk. Version 1 frames describe a single
+   reception at the top level, version 2 ones (jver 2, sent by gateways with
+   several antennas or concentrators) carry one rsig object per antenna.
+   An Uplink holds every field of either, with the reception of a version 1
+   frame as the only entry of `rsig`.
+
+   Fields only one version models travel in `extra` through the other one,
+   so that converting back restores them: the rfch of version 1 frames, and
+   the tmms and time of version 2 ones, which lora_pkt_fwd also sends in
+   version 1 frames. Converting down to version 1 keeps the best reception
+   only, and drops brd, aesk and delayed.
+*/
+use super::{
+    push_data::{self, RSig, RxPk, RxPkV1, RxPkV2, CRC},
+    CodingRate, DataRate, Modulation,
+};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+const JSON_VERSION: usize = 2;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ConversionError {
+    #[error("no reception to describe in a version 1 rxpk")]
+    NoReception,
+    #[error("unknown modulation {0}")]
+    UnknownModulation(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Uplink {
+    pub tmst: u32,
+    pub tmms: Option<u64>,
+    pub time: Option<String>,
+    pub freq: f64,
+    /// RF chain, only sent in version 1 frames
+    pub rfch: Option<u64>,
+    /// concentrator board, 0 for version 1 frames
+    pub brd: usize,
+    pub aesk: usize,
+    pub delayed: Option<bool>,
+    pub modu: String,
+    pub datr: DataRate,
+    pub codr: CodingRate,
+    pub stat: CRC,
+    pub size: u64,
+    pub data: Vec<u8>,
+    /// one per antenna which received the frame
+    pub rsig: Vec<RSig>,
+    pub extra: Map<String, Value>,
+}
+
+impl Uplink {
+    pub fn modulation(&self) -> Option<Modulation> {
+        match self.modu.as_str() {
+            "LORA" => Some(Modulation::LORA),
+            "FSK" => Some(Modulation::FSK),
+            _ => None,
+        }
+    }
+
+    /// Reception with the highest SNR, then RSSI
+    pub fn best_rsig(&self) -> Option<&RSig> {
+        push_data::best_rsig(&self.rsig)
+    }
+}
+
+impl From<RxPkV1> for Uplink {
+    fn from(pk: RxPkV1) -> Uplink {
+        let rsig = pk.rsig();
+        let mut extra = pk.extra;
+        let tmms = extra.remove("tmms").and_then(|tmms| tmms.as_u64());
+        let time = match extra.remove("time") {
+            Some(Value::String(time)) => Some(time),
+            _ => None,
+        };
+        let modu = match pk.modu {
+            Modulation::LORA => "LORA",
+            Modulation::FSK => "FSK",
+        };
+        Uplink {
+            tmst: pk.tmst,
+            tmms,
+            time,
+            freq: pk.freq,
+            rfch: Some(pk.rfch),
+            brd: 0,
+            aesk: 0,
+            delayed: None,
+            modu: modu.to_string(),
+            datr: pk.datr,
+            codr: pk.codr,
+            stat: pk.stat,
+            size: pk.size,
+            data: pk.data,
+            rsig: vec![rsig],
+            extra,
+        }
+    }
+}
+
+impl From<RxPkV2> for Uplink {
+    fn from(pk: RxPkV2) -> Uplink {
+        let mut extra = pk.extra;
+        let rfch = extra.remove("rfch").and_then(|rfch| rfch.as_u64());
+        Uplink {
+            tmst: pk.tmst,
+            tmms: pk.tmms,
+            time: pk.time,
+            freq: pk.freq,
+            rfch,
+            brd: pk.brd,
+            aesk: pk.aesk,
+            delayed: pk.delayed,
+            modu: pk.modu,
+            datr: pk.datr,
+            codr: pk.codr,
+            stat: pk.stat,
+            size: pk.size,
+            data: pk.data,
+            rsig: pk.rsig,
+            extra,
+        }
+    }
+}
+
+impl From<RxPk> for Uplink {
+    fn from(rxpk: RxPk) -> Uplink {
+        match rxpk {
+            RxPk::V1(pk) => pk.into(),
+            RxPk::V2(pk) => pk.into(),
+        }
+    }
+}
+
+impl From<Uplink> for RxPkV2 {
+    fn from(uplink: Uplink) -> RxPkV2 {
+        let mut extra = uplink.extra;
+        if let Some(rfch) = uplink.rfch {
+            extra.insert("rfch".into(), rfch.into());
+        }
+        RxPkV2 {
+            aesk: uplink.aesk,
+            brd: uplink.brd,
+            codr: uplink.codr,
+            data: uplink.data,
+            datr: uplink.datr,
+            freq: uplink.freq,
+            jver: JSON_VERSION,
+            modu: uplink.modu,
+            rsig: uplink.rsig,
+            size: uplink.size,
+            stat: uplink.stat,
+            tmst: uplink.tmst,
+            delayed: uplink.delayed,
+            tmms: uplink.tmms,
+            time: uplink.time,
+            extra,
+        }
+    }
+}
+
+impl TryFrom<Uplink> for RxPkV1 {
+    type Error = ConversionError;
+
+    fn try_from(uplink: Uplink) -> Result<RxPkV1, ConversionError> {
+        let modu = uplink
+            .modulation()
+            .ok_or_else(|| ConversionError::UnknownModulation(uplink.modu.clone()))?;
+        let best = uplink.best_rsig().ok_or(ConversionError::NoReception)?;
+        let (chan, rssi, rssis, lsnr) = (best.chan, best.rssic, best.rssis, best.lsnr);
+        let mut extra = uplink.extra;
+        if let Some(tmms) = uplink.tmms {
+            extra.insert("tmms".into(), tmms.into());
+        }
+        if let Some(time) = uplink.time {
+            extra.insert("time".into(), time.into());
+        }
+        Ok(RxPkV1 {
+            chan,
+            codr: uplink.codr,
+            data: uplink.data,
+            datr: uplink.datr,
+            freq: uplink.freq,
+            lsnr,
+            modu,
+            rfch: uplink.rfch.unwrap_or_default(),
+            rssi,
+            rssis,
+            size: uplink.size,
+            stat: uplink.stat,
+            tmst: uplink.tmst,
+            extra,
+        })
+    }
+}
+
+impl From<RxPkV1> for RxPkV2 {
+    fn from(pk: RxPkV1) -> RxPkV2 {
+        Uplink::from(pk).into()
+    }
+}
+
+impl TryFrom<RxPkV2> for RxPkV1 {
+    type Error = ConversionError;
+
+    /// Keeps the best reception only, eg: for servers which predate jver 2
+    fn try_from(pk: RxPkV2) -> Result<RxPkV1, ConversionError> {
+        RxPkV1::try_from(Uplink::from(pk))
+    }
+}
+
+impl RxPk {
+    /// Normalized copy, whatever the version of the frame
+    pub fn to_uplink(&self) -> Uplink {
+        self.clone().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2() -> RxPkV2 {
+        serde_json::from_str(
+            r#"{"jver":2,"brd":1,"aesk":0,"tmst":1000,"tmms":1300000000000,
+                "time":"2021-03-17T18:47:01.123456Z","freq":868.1,"modu":"LORA",
+                "datr":"SF7BW125","codr":"4/5","stat":1,"size":2,"data":"AAE=",
+                "rsig":[{"ant":0,"chan":3,"rssic":-110,"lsnr":-12.5},
+                        {"ant":1,"chan":3,"rssic":-100,"lsnr":-4.25,"etime":"vX8="}]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_down_conversion() {
+        let v1 = RxPkV1::try_from(v2()).unwrap();
+        assert_eq!((v1.chan, v1.rssi, v1.lsnr), (3, -100, -4.25));
+        assert_eq!(v1.extra["tmms"], 1_300_000_000_000u64);
+        let json = serde_json::to_value(&v1).unwrap();
+        assert!(json.get("rsig").is_none());
+
+        // back up, with the one reception left
+        let uplink = Uplink::from(RxPkV2::from(v1));
+        assert_eq!(uplink.tmms, Some(1_300_000_000_000));
+        assert_eq!(uplink.rfch, Some(0));
+        assert_eq!(uplink.rsig.len(), 1);
+        assert_eq!(uplink.best_rsig().unwrap().lsnr, -4.25);
+
+        let mut empty = v2();
+        empty.rsig.clear();
+        assert!(matches!(
+            RxPkV1::try_from(empty),
+            Err(ConversionError::NoReception)
+        ));
+    }
+
+    #[test]
+    fn test_up_conversion() {
+        let v1 = RxPkV1::try_from(v2()).unwrap();
+        let v2 = RxPkV2::from(RxPkV1 { rfch: 1, ..v1 });
+        assert_eq!(v2.jver, 2);
+        assert_eq!(v2.tmms, Some(1_300_000_000_000));
+        assert!(v2.extra.get("tmms").is_none());
+        // rfch has no place in version 2 but survives the round trip
+        assert_eq!(RxPkV1::try_from(v2).unwrap().rfch, 1);
+    }
+}
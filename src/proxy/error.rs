@@ -0,0 +1,19 @@
+use super::InternalEvent;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Io Error from using UDP")]
+    UdpError(#[from] std::io::Error),
+    #[error("Internal queue closed or full")]
+    InternalQueueClosedOrFull,
+    #[error("proxy configured with no servers")]
+    NoServers,
+}
+
+impl From<mpsc::error::SendError<InternalEvent>> for Error {
+    fn from(_err: mpsc::error::SendError<InternalEvent>) -> Error {
+        Error::InternalQueueClosedOrFull
+    }
+}
@@ -0,0 +1,468 @@
+/*
+   This module relays raw GWMP datagrams between packet forwarders and one
+   or more servers without parsing their JSON, so fields this crate does not
+   model reach the servers untouched. Each gateway gets its own pair of up
+   and down sockets towards every server, the way lora_pkt_fwd connects, so
+   that servers keep seeing one binding per gateway.
+
+   Only the 4 byte header and the gateway MAC are looked at:
+   - PUSH_DATA and PULL_DATA go to every server and the first ack of each
+     token is relayed back to the gateway
+   - PULL_RESP keep their token, unless another downlink pending on the same
+     gateway already uses it, in which case a free token is substituted
+   - TX_ACK only go to the server which sent the downlink, with its token
+*/
+use crate::{Identifier, MacAddress, ParseError, UnparsedFrame};
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{net::UdpSocket, sync::broadcast, sync::mpsc};
+
+mod error;
+pub use error::Error;
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+// largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM: usize = 65_507;
+// protocol version, random token and identifier
+const HEADER_LEN: usize = 4;
+const MAC_END: usize = HEADER_LEN + 8;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// address the gateways connect to
+    pub listen: SocketAddr,
+    /// every gateway datagram is relayed to each of these servers
+    pub servers: Vec<SocketAddr>,
+    /// how long a datagram waits for its ack before being forgotten
+    pub ack_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// first datagram of a gateway, which is now bound to every server
+    GatewayConnected(MacAddress, SocketAddr),
+    /// the token of a PULL_RESP was already pending on the gateway,
+    /// the downlink was sent with the second token instead
+    TokenRewritten(MacAddress, u16, u16),
+    UnableToParseUdpFrame(UnparsedFrame),
+}
+
+#[derive(Debug)]
+pub(crate) enum InternalEvent {
+    FromGateway(Vec<u8>, SocketAddr),
+    FromServer(MacAddress, usize, Vec<u8>),
+}
+
+// sockets a gateway uses towards one server
+struct Binding {
+    server: SocketAddr,
+    up: Arc<UdpSocket>,
+    down: Arc<UdpSocket>,
+}
+
+struct Gateway {
+    // where the gateway sends PUSH_DATA from and expects PUSH_ACK
+    up: Option<SocketAddr>,
+    // where the gateway sends PULL_DATA from and expects PULL_RESP
+    down: Option<SocketAddr>,
+    bindings: Vec<Binding>,
+    // uplink datagrams which have not been acked by any server yet
+    awaiting_ack: HashMap<(Identifier, u16), Instant>,
+    // token on the gateway side -> server and token on the server side
+    downlinks: HashMap<u16, (usize, u16, Instant)>,
+}
+
+pub struct Proxy {
+    servers: Vec<SocketAddr>,
+    ack_timeout: Duration,
+    socket: Arc<UdpSocket>,
+    sender: mpsc::Sender<InternalEvent>,
+    receiver: mpsc::Receiver<InternalEvent>,
+    gateways: HashMap<MacAddress, Gateway>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Proxy {
+    pub async fn new(config: Config) -> Result<Proxy> {
+        if config.servers.is_empty() {
+            return Err(Error::NoServers);
+        }
+        let socket = Arc::new(UdpSocket::bind(config.listen).await?);
+        let (sender, receiver) = mpsc::channel(100);
+        let (events, _) = broadcast::channel(1024);
+
+        // reads datagrams of the gateways
+        let (gateway_socket, gateway_sender) = (socket.clone(), sender.clone());
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                match gateway_socket.recv_from(&mut buf).await {
+                    Ok((n, src)) => {
+                        let event = InternalEvent::FromGateway(buf[..n].to_vec(), src);
+                        if gateway_sender.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    // we panic here because the only error case
+                    // is if we lost the local socket somehow
+                    Err(e) => panic!("Proxy lost its gateway socket: {:?}", e),
+                }
+            }
+        });
+
+        Ok(Proxy {
+            servers: config.servers,
+            ack_timeout: config.ack_timeout,
+            socket,
+            sender,
+            receiver,
+            gateways: HashMap::new(),
+            events,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Address the gateways connect to, eg: the port picked when listening on port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn run(mut self) -> Result {
+        while let Some(event) = self.receiver.recv().await {
+            match event {
+                InternalEvent::FromGateway(datagram, src) => {
+                    self.handle_gateway(datagram, src).await
+                }
+                InternalEvent::FromServer(mac, server, datagram) => {
+                    self.handle_server(mac, server, datagram).await
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_gateway(&mut self, mut datagram: Vec<u8>, src: SocketAddr) {
+        let (id, token) = match header(&datagram) {
+            Ok(_) if datagram.len() < MAC_END => {
+                let error = ParseError::TooShort(datagram.len());
+                self.unparseable(&datagram, src, error);
+                return;
+            }
+            Ok(header) => header,
+            Err(error) => {
+                self.unparseable(&datagram, src, error);
+                return;
+            }
+        };
+        let mac = MacAddress::new(array_ref![datagram, HEADER_LEN, 8]);
+        if !self.gateways.contains_key(&mac) {
+            // the next datagram of the gateway tries again
+            let gateway = match self.bind(mac).await {
+                Ok(gateway) => gateway,
+                Err(e) => {
+                    warn!(
+                        "Unable to bind {} to the servers, dropping datagram: {}",
+                        mac, e
+                    );
+                    return;
+                }
+            };
+            self.gateways.insert(mac, gateway);
+            // nobody listening for events is fine
+            let _ = self.events.send(Event::GatewayConnected(mac, src));
+        }
+        let ack_timeout = self.ack_timeout;
+        let gateway = self.gateways.get_mut(&mac).unwrap();
+        gateway.expire(ack_timeout);
+
+        match id {
+            Identifier::PushData => {
+                gateway.up = Some(src);
+                gateway
+                    .awaiting_ack
+                    .insert((Identifier::PushData, token), Instant::now());
+                for binding in &gateway.bindings {
+                    send(&binding.up, &datagram, binding.server).await;
+                }
+            }
+            Identifier::PullData => {
+                gateway.down = Some(src);
+                gateway
+                    .awaiting_ack
+                    .insert((Identifier::PullData, token), Instant::now());
+                for binding in &gateway.bindings {
+                    send(&binding.down, &datagram, binding.server).await;
+                }
+            }
+            Identifier::TxAck => match gateway.downlinks.remove(&token) {
+                Some((server, server_token, _)) => {
+                    set_token(&mut datagram, server_token);
+                    let binding = &gateway.bindings[server];
+                    send(&binding.down, &datagram, binding.server).await;
+                }
+                None => warn!("{} TX_ACK for unknown token {}", mac, token),
+            },
+            _ => self.unparseable(&datagram, src, ParseError::UnexpectedIdentifier(id)),
+        }
+    }
+
+    async fn handle_server(&mut self, mac: MacAddress, server: usize, mut datagram: Vec<u8>) {
+        let src = self.servers[server];
+        let (id, token) = match header(&datagram) {
+            Ok(header) => header,
+            Err(error) => {
+                self.unparseable(&datagram, src, error);
+                return;
+            }
+        };
+        let gateway = match self.gateways.get_mut(&mac) {
+            Some(gateway) => gateway,
+            None => return,
+        };
+
+        match id {
+            Identifier::PushAck | Identifier::PullAck => {
+                let (acked, dst) = if id == Identifier::PushAck {
+                    (Identifier::PushData, gateway.up)
+                } else {
+                    (Identifier::PullData, gateway.down)
+                };
+                // the other servers ack the same datagram, only relay the first
+                if gateway.awaiting_ack.remove(&(acked, token)).is_some() {
+                    if let Some(dst) = dst {
+                        send(&self.socket, &datagram, dst).await;
+                    }
+                }
+            }
+            Identifier::PullResp => {
+                let dst = match gateway.down {
+                    Some(dst) => dst,
+                    None => {
+                        warn!("{} PULL_RESP before any PULL_DATA", mac);
+                        return;
+                    }
+                };
+                let mut gateway_token = token;
+                while gateway.downlinks.contains_key(&gateway_token) {
+                    gateway_token = rand::random();
+                }
+                if gateway_token != token {
+                    set_token(&mut datagram, gateway_token);
+                    let _ = self
+                        .events
+                        .send(Event::TokenRewritten(mac, token, gateway_token));
+                }
+                gateway
+                    .downlinks
+                    .insert(gateway_token, (server, token, Instant::now()));
+                send(&self.socket, &datagram, dst).await;
+            }
+            _ => self.unparseable(&datagram, src, ParseError::UnexpectedIdentifier(id)),
+        }
+    }
+
+    async fn bind(&self, mac: MacAddress) -> Result<Gateway> {
+        let mut bindings = Vec::with_capacity(self.servers.len());
+        for (index, server) in self.servers.iter().enumerate() {
+            let local = if server.is_ipv4() {
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+            } else {
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+            };
+            let up = Arc::new(UdpSocket::bind(local).await?);
+            let down = Arc::new(UdpSocket::bind(local).await?);
+            for socket in [&up, &down] {
+                tokio::spawn(relay(
+                    socket.clone(),
+                    *server,
+                    mac,
+                    index,
+                    self.sender.clone(),
+                ));
+            }
+            bindings.push(Binding {
+                server: *server,
+                up,
+                down,
+            });
+        }
+        Ok(Gateway {
+            up: None,
+            down: None,
+            bindings,
+            awaiting_ack: HashMap::new(),
+            downlinks: HashMap::new(),
+        })
+    }
+
+    fn unparseable(&self, datagram: &[u8], src: SocketAddr, error: ParseError) {
+        let frame = UnparsedFrame::new(datagram, src, error);
+        let _ = self.events.send(Event::UnableToParseUdpFrame(frame));
+    }
+}
+
+impl Gateway {
+    fn expire(&mut self, timeout: Duration) {
+        self.awaiting_ack.retain(|_, sent| sent.elapsed() < timeout);
+        self.downlinks
+            .retain(|_, (_, _, sent)| sent.elapsed() < timeout);
+    }
+}
+
+// reads the datagrams a server sends to one of the sockets of a gateway
+async fn relay(
+    socket: Arc<UdpSocket>,
+    server: SocketAddr,
+    mac: MacAddress,
+    index: usize,
+    sender: mpsc::Sender<InternalEvent>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((n, src)) if src == server => {
+                let event = InternalEvent::FromServer(mac, index, buf[..n].to_vec());
+                if sender.send(event).await.is_err() {
+                    return;
+                }
+            }
+            Ok((_, src)) => warn!("{} ignoring datagram from {}", mac, src),
+            Err(e) => {
+                warn!("{} lost its socket to {}: {:?}", mac, server, e);
+                return;
+            }
+        }
+    }
+}
+
+async fn send(socket: &UdpSocket, datagram: &[u8], dst: SocketAddr) {
+    if let Err(e) = socket.send_to(datagram, dst).await {
+        warn!("Unable to send datagram to {}: {:?}", dst, e);
+    }
+}
+
+fn header(datagram: &[u8]) -> std::result::Result<(Identifier, u16), ParseError> {
+    if datagram.len() < HEADER_LEN {
+        return Err(ParseError::TooShort(datagram.len()));
+    }
+    // forwarded untouched, so the older protocol version 1 goes through too
+    if !matches!(datagram[0], 1 | 2) {
+        return Err(ParseError::InvalidProtocolVersion);
+    }
+    let id = Identifier::try_from(datagram[3]).map_err(|_| ParseError::InvalidIdentifier)?;
+    Ok((id, u16::from_be_bytes([datagram[1], datagram[2]])))
+}
+
+fn set_token(datagram: &mut [u8], token: u16) {
+    datagram[1..3].copy_from_slice(&token.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 8] = [0xaa, 0x55, 0x5a, 0, 0, 0, 0, 1];
+
+    fn datagram(token: u16, id: Identifier, mac: bool, json: &str) -> Vec<u8> {
+        let mut datagram = vec![2];
+        datagram.extend_from_slice(&token.to_be_bytes());
+        datagram.push(id as u8);
+        if mac {
+            datagram.extend_from_slice(&MAC);
+        }
+        datagram.extend_from_slice(json.as_bytes());
+        datagram
+    }
+
+    async fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let (n, src) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        (buf[..n].to_vec(), src)
+    }
+
+    #[test]
+    fn test_header_version() {
+        let mut pull_data = datagram(7, Identifier::PullData, true, "");
+        assert_eq!(header(&pull_data).unwrap(), (Identifier::PullData, 7));
+        pull_data[0] = 1;
+        assert_eq!(header(&pull_data).unwrap(), (Identifier::PullData, 7));
+        pull_data[0] = 3;
+        assert!(matches!(
+            header(&pull_data),
+            Err(ParseError::InvalidProtocolVersion)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transparent_relay() {
+        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server_a = UdpSocket::bind(local).await.unwrap();
+        let server_b = UdpSocket::bind(local).await.unwrap();
+        let gateway = UdpSocket::bind(local).await.unwrap();
+        let proxy = Proxy::new(Config {
+            listen: local,
+            servers: vec![
+                server_a.local_addr().unwrap(),
+                server_b.local_addr().unwrap(),
+            ],
+            ack_timeout: Duration::from_secs(5),
+        })
+        .await
+        .unwrap();
+        let listen = proxy.local_addr().unwrap();
+        tokio::spawn(proxy.run());
+
+        // fields unknown to this crate reach both servers untouched
+        let json = r#"{"rxpk":[{"tmst":1,"vendor":{"x":[1,2]}}]}"#;
+        let push_data = datagram(0x1234, Identifier::PushData, true, json);
+        gateway.send_to(&push_data, listen).await.unwrap();
+        let (received_a, binding_a) = recv(&server_a).await;
+        let (received_b, binding_b) = recv(&server_b).await;
+        assert_eq!(received_a, push_data);
+        assert_eq!(received_b, push_data);
+
+        // only the first ack is relayed
+        let push_ack = datagram(0x1234, Identifier::PushAck, false, "");
+        server_a.send_to(&push_ack, binding_a).await.unwrap();
+        server_b.send_to(&push_ack, binding_b).await.unwrap();
+        assert_eq!(recv(&gateway).await.0, push_ack);
+
+        let pull_data = datagram(7, Identifier::PullData, true, "");
+        gateway.send_to(&pull_data, listen).await.unwrap();
+        let (_, down_a) = recv(&server_a).await;
+        let (_, down_b) = recv(&server_b).await;
+        assert_ne!(down_a, binding_a);
+
+        // both servers pick the same token, the second one is rewritten
+        let pull_resp = datagram(42, Identifier::PullResp, false, r#"{"txpk":{"x":1}}"#);
+        server_a.send_to(&pull_resp, down_a).await.unwrap();
+        let (first, _) = recv(&gateway).await;
+        assert_eq!(first, pull_resp);
+        server_b.send_to(&pull_resp, down_b).await.unwrap();
+        let (second, _) = recv(&gateway).await;
+        let rewritten = header(&second).unwrap().1;
+        assert_ne!(rewritten, 42);
+        assert_eq!(second[HEADER_LEN..], pull_resp[HEADER_LEN..]);
+
+        // each TX_ACK goes back to its own server with the original token
+        let tx_ack = datagram(rewritten, Identifier::TxAck, true, "");
+        gateway.send_to(&tx_ack, listen).await.unwrap();
+        assert_eq!(
+            recv(&server_b).await.0,
+            datagram(42, Identifier::TxAck, true, "")
+        );
+        let tx_ack = datagram(42, Identifier::TxAck, true, "");
+        gateway.send_to(&tx_ack, listen).await.unwrap();
+        assert_eq!(recv(&server_a).await.0, tx_ack);
+    }
+}
@@ -0,0 +1,614 @@
+/*
+   Channel plans and data rate tables of the LoRaWAN regional parameters,
+   to map rxpk and txpk to channel and DR indices and to tell packets which
+   do not fit the plan of the network apart.
+
+   Frequencies are handled in Hz internally so that 868.1 and 868.100000001
+   designate the same channel. DRs which are neither LoRa nor within the
+   SF7 to SF12 range supported by DataRate, like FSK and LR-FHSS, are left
+   out of the tables.
+*/
+use crate::{pull_resp::TxPk, push_data::RxPk, Bandwidth, DataRate, SpreadingFactor};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("unknown region {0}")]
+    UnknownRegion(String),
+    #[error("{0} MHz is not a channel of the plan")]
+    FrequencyOutOfPlan(f64),
+    #[error("{0} is not a DR of the plan")]
+    DataRateOutOfPlan(DataRate),
+    #[error("DR{dr} is not allowed on {frequency} MHz")]
+    DataRateNotAllowed { dr: usize, frequency: f64 },
+    #[error("{powe} dBm exceeds the {max} dBm allowed on {frequency} MHz")]
+    TxPowerTooHigh { powe: u64, max: u64, frequency: f64 },
+    #[error("RX1 DR offset {0} is not defined in {1}")]
+    Rx1DrOffsetOutOfPlan(usize, Region),
+    #[error("Class B beacons are not defined in {0}")]
+    NoBeaconInRegion(Region),
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    EU868,
+    US915,
+    AS923,
+    AU915,
+    IN865,
+    KR920,
+    CN470,
+}
+
+impl FromStr for Region {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "EU868" => Ok(Region::EU868),
+            "US915" => Ok(Region::US915),
+            "AS923" => Ok(Region::AS923),
+            "AU915" => Ok(Region::AU915),
+            "IN865" => Ok(Region::IN865),
+            "KR920" => Ok(Region::KR920),
+            "CN470" => Ok(Region::CN470),
+            _ => Err(Error::UnknownRegion(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+use Bandwidth::*;
+use SpreadingFactor::*;
+
+type DrTable = [Option<(SpreadingFactor, Bandwidth)>; 14];
+
+// DR0 to DR5 of most regions, DR6 is SF7BW250 where defined
+const EU_DRS: DrTable = [
+    Some((SF12, BW125)),
+    Some((SF11, BW125)),
+    Some((SF10, BW125)),
+    Some((SF9, BW125)),
+    Some((SF8, BW125)),
+    Some((SF7, BW125)),
+    Some((SF7, BW250)),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+const US_DRS: DrTable = [
+    Some((SF10, BW125)),
+    Some((SF9, BW125)),
+    Some((SF8, BW125)),
+    Some((SF7, BW125)),
+    Some((SF8, BW500)),
+    None,
+    None,
+    None,
+    Some((SF12, BW500)),
+    Some((SF11, BW500)),
+    Some((SF10, BW500)),
+    Some((SF9, BW500)),
+    Some((SF8, BW500)),
+    Some((SF7, BW500)),
+];
+
+const AU_DRS: DrTable = [
+    Some((SF12, BW125)),
+    Some((SF11, BW125)),
+    Some((SF10, BW125)),
+    Some((SF9, BW125)),
+    Some((SF8, BW125)),
+    Some((SF7, BW125)),
+    Some((SF8, BW500)),
+    None,
+    Some((SF12, BW500)),
+    Some((SF11, BW500)),
+    Some((SF10, BW500)),
+    Some((SF9, BW500)),
+    Some((SF8, BW500)),
+    Some((SF7, BW500)),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel {
+    /// MHz
+    pub frequency: f64,
+    pub min_dr: usize,
+    pub max_dr: usize,
+}
+
+impl Channel {
+    pub fn new(frequency: f64, dr: RangeInclusive<usize>) -> Channel {
+        Channel {
+            frequency,
+            min_dr: *dr.start(),
+            max_dr: *dr.end(),
+        }
+    }
+}
+
+/// Channel and DR of an uplink which fits the plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uplink {
+    pub channel: usize,
+    pub dr: usize,
+}
+
+/// Frequency range with the highest TX power a gateway may use in it and,
+/// in regions which limit it, the share of time it may transmit there
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubBand {
+    low: u32,
+    high: u32,
+    max_tx_power: u64,
+    duty_cycle: Option<f64>,
+}
+
+impl SubBand {
+    /// Lowest frequency in MHz
+    pub fn low(&self) -> f64 {
+        mhz(self.low)
+    }
+
+    /// Highest frequency in MHz
+    pub fn high(&self) -> f64 {
+        mhz(self.high)
+    }
+
+    /// dBm
+    pub fn max_tx_power(&self) -> u64 {
+        self.max_tx_power
+    }
+
+    /// Share of time a gateway may transmit in the sub-band, eg: 0.01 for 1%
+    pub fn duty_cycle(&self) -> Option<f64> {
+        self.duty_cycle
+    }
+
+    pub fn contains(&self, frequency: f64) -> bool {
+        (self.low..=self.high).contains(&hz(frequency))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    region: Region,
+    drs: &'static DrTable,
+    uplink_drs: RangeInclusive<usize>,
+    downlink_drs: RangeInclusive<usize>,
+    uplink_channels: Vec<Channel>,
+    // None where downlinks use the uplink channels
+    downlink_channels: Option<Vec<f64>>,
+    bands: Vec<SubBand>,
+    rx2: (f64, usize),
+    dwell_time: Option<Duration>,
+}
+
+fn hz(mhz: f64) -> u32 {
+    (mhz * 1_000_000.0).round() as u32
+}
+
+fn mhz(hz: u32) -> f64 {
+    hz as f64 / 1_000_000.0
+}
+
+// `count` frequencies every `step` Hz from `first` Hz
+fn raster(first: u32, step: u32, count: u32) -> Vec<f64> {
+    (0..count).map(|n| mhz(first + n * step)).collect()
+}
+
+fn channels(frequencies: Vec<f64>, dr: RangeInclusive<usize>) -> Vec<Channel> {
+    frequencies
+        .into_iter()
+        .map(|frequency| Channel::new(frequency, dr.clone()))
+        .collect()
+}
+
+fn band(low: f64, high: f64, max_tx_power: u64) -> SubBand {
+    SubBand {
+        low: hz(low),
+        high: hz(high),
+        max_tx_power,
+        duty_cycle: None,
+    }
+}
+
+fn duty_cycled(low: f64, high: f64, max_tx_power: u64, duty_cycle: f64) -> SubBand {
+    SubBand {
+        duty_cycle: Some(duty_cycle),
+        ..band(low, high, max_tx_power)
+    }
+}
+
+impl Plan {
+    /// Default channels of the region; regions with a configurable plan,
+    /// like EU868, start with their mandatory channels only
+    pub fn new(region: Region) -> Plan {
+        let plan = |drs, uplink_channels, bands, rx2| Plan {
+            region,
+            drs,
+            uplink_drs: 0..=5,
+            downlink_drs: 0..=5,
+            uplink_channels,
+            downlink_channels: None,
+            bands,
+            rx2,
+            dwell_time: None,
+        };
+        match region {
+            Region::EU868 => Plan {
+                uplink_drs: 0..=6,
+                downlink_drs: 0..=6,
+                ..plan(
+                    &EU_DRS,
+                    channels(vec![868.1, 868.3, 868.5], 0..=5),
+                    // sub-bands of ETSI EN 300 220 which LoRaWAN uses
+                    vec![
+                        duty_cycled(863.0, 865.0, 16, 0.001),
+                        duty_cycled(865.0, 868.0, 16, 0.01),
+                        duty_cycled(868.0, 868.6, 16, 0.01),
+                        duty_cycled(868.7, 869.2, 16, 0.001),
+                        // 500 mW and 10% in sub-band g3, used for RX2
+                        duty_cycled(869.4, 869.65, 27, 0.1),
+                        duty_cycled(869.7, 870.0, 16, 0.01),
+                    ],
+                    (869.525, 0),
+                )
+            },
+            Region::US915 => {
+                let mut uplink = channels(raster(902_300_000, 200_000, 64), 0..=3);
+                uplink.extend(channels(raster(903_000_000, 1_600_000, 8), 4..=4));
+                Plan {
+                    uplink_drs: 0..=4,
+                    downlink_drs: 8..=13,
+                    downlink_channels: Some(raster(923_300_000, 600_000, 8)),
+                    ..plan(&US_DRS, uplink, vec![band(902.0, 928.0, 30)], (923.3, 8))
+                }
+            }
+            Region::AU915 => {
+                let mut uplink = channels(raster(915_200_000, 200_000, 64), 0..=5);
+                uplink.extend(channels(raster(915_900_000, 1_600_000, 8), 6..=6));
+                Plan {
+                    uplink_drs: 0..=6,
+                    downlink_drs: 8..=13,
+                    downlink_channels: Some(raster(923_300_000, 600_000, 8)),
+                    ..plan(&AU_DRS, uplink, vec![band(915.0, 928.0, 30)], (923.3, 8))
+                }
+            }
+            Region::AS923 => Plan {
+                uplink_drs: 0..=6,
+                downlink_drs: 0..=6,
+                // required in Japan and most other AS923 countries
+                dwell_time: Some(Duration::from_millis(400)),
+                ..plan(
+                    &EU_DRS,
+                    channels(vec![923.2, 923.4], 0..=5),
+                    vec![band(915.0, 928.0, 16)],
+                    (923.2, 2),
+                )
+            },
+            Region::IN865 => plan(
+                &EU_DRS,
+                channels(vec![865.0625, 865.4025, 865.985], 0..=5),
+                vec![band(865.0, 867.0, 30)],
+                (866.55, 2),
+            ),
+            Region::KR920 => plan(
+                &EU_DRS,
+                channels(vec![922.1, 922.3, 922.5], 0..=5),
+                vec![band(920.9, 923.3, 23)],
+                (921.9, 0),
+            ),
+            Region::CN470 => Plan {
+                downlink_channels: Some(raster(500_300_000, 200_000, 48)),
+                ..plan(
+                    &EU_DRS,
+                    channels(raster(470_300_000, 200_000, 96), 0..=5),
+                    vec![band(470.0, 510.0, 19)],
+                    (505.3, 0),
+                )
+            },
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.uplink_channels
+    }
+
+    /// Adds a channel to the plan, eg: one of the CFList of EU868
+    pub fn add_channel(&mut self, channel: Channel) -> Result<(), Error> {
+        if !self.in_band(channel.frequency) {
+            return Err(Error::FrequencyOutOfPlan(channel.frequency));
+        }
+        for dr in [channel.min_dr, channel.max_dr] {
+            if !self.uplink_drs.contains(&dr) || self.data_rate(dr).is_none() {
+                return Err(Error::DataRateNotAllowed {
+                    dr,
+                    frequency: channel.frequency,
+                });
+            }
+        }
+        self.uplink_channels.push(channel);
+        Ok(())
+    }
+
+    /// Default RX2 frequency in MHz and DR
+    pub fn rx2(&self) -> (f64, usize) {
+        self.rx2
+    }
+
+    pub fn data_rate(&self, dr: usize) -> Option<DataRate> {
+        self.drs
+            .get(dr)
+            .cloned()
+            .flatten()
+            .map(|(sf, bw)| DataRate::new(sf, bw))
+    }
+
+    pub fn uplink_dr(&self, datr: &DataRate) -> Option<usize> {
+        self.dr(datr, &self.uplink_drs)
+    }
+
+    pub fn downlink_dr(&self, datr: &DataRate) -> Option<usize> {
+        self.dr(datr, &self.downlink_drs)
+    }
+
+    fn dr(&self, datr: &DataRate, range: &RangeInclusive<usize>) -> Option<usize> {
+        range
+            .clone()
+            .find(|dr| self.data_rate(*dr).as_ref() == Some(datr))
+    }
+
+    pub fn channel_index(&self, frequency: f64) -> Option<usize> {
+        self.uplink_channels
+            .iter()
+            .position(|channel| hz(channel.frequency) == hz(frequency))
+    }
+
+    pub fn sub_bands(&self) -> &[SubBand] {
+        &self.bands
+    }
+
+    /// Sub-band a frequency falls in, None when it is out of the plan
+    pub fn sub_band(&self, frequency: f64) -> Option<&SubBand> {
+        self.bands.iter().find(|band| band.contains(frequency))
+    }
+
+    /// Highest TX power in dBm a gateway may use on this frequency
+    pub fn max_tx_power(&self, frequency: f64) -> Option<u64> {
+        self.sub_band(frequency).map(|band| band.max_tx_power)
+    }
+
+    /// Longest a single transmission may last, in regions which limit it
+    pub fn dwell_time(&self) -> Option<Duration> {
+        self.dwell_time
+    }
+
+    /// Frequency in MHz of the RX1 window opened by an uplink: the uplink
+    /// frequency itself, or the downlink channel it maps to in regions with
+    /// dedicated downlink channels
+    pub fn rx1_frequency(&self, uplink_frequency: f64) -> Result<f64, Error> {
+        match &self.downlink_channels {
+            Some(downlink) => {
+                let channel = self
+                    .channel_index(uplink_frequency)
+                    .ok_or(Error::FrequencyOutOfPlan(uplink_frequency))?;
+                Ok(downlink[channel % downlink.len()])
+            }
+            None => Ok(uplink_frequency),
+        }
+    }
+
+    /// DR of the RX1 window opened by an uplink sent on `dr`
+    pub fn rx1_dr(&self, dr: usize, rx1_dr_offset: usize) -> Result<usize, Error> {
+        let max_offset = match self.region {
+            Region::US915 => 3,
+            Region::AS923 | Region::IN865 => 7,
+            _ => 5,
+        };
+        if rx1_dr_offset > max_offset {
+            return Err(Error::Rx1DrOffsetOutOfPlan(rx1_dr_offset, self.region));
+        }
+        let (dr, offset) = (dr as i64, rx1_dr_offset as i64);
+        let rx1 = match self.region {
+            Region::US915 => (10 + dr - offset).clamp(8, 13),
+            Region::AU915 => (8 + dr - offset).clamp(8, 13),
+            // offsets 6 and 7 raise the DR by 1 and 2
+            Region::AS923 | Region::IN865 if offset > 5 => (dr + offset - 5).min(5),
+            _ => (dr - offset).max(0),
+        };
+        Ok(rx1 as usize)
+    }
+
+    fn in_band(&self, frequency: f64) -> bool {
+        self.max_tx_power(frequency).is_some()
+    }
+
+    /// Channel and DR of an uplink, or why it does not fit the plan
+    pub fn check_rxpk(&self, rxpk: &RxPk) -> Result<Uplink, Error> {
+        let frequency = *rxpk.get_frequency();
+        let datr = rxpk.get_datarate();
+        let channel = self
+            .channel_index(frequency)
+            .ok_or(Error::FrequencyOutOfPlan(frequency))?;
+        let dr = self
+            .uplink_dr(&datr)
+            .ok_or(Error::DataRateOutOfPlan(datr))?;
+        let allowed = &self.uplink_channels[channel];
+        if dr < allowed.min_dr || dr > allowed.max_dr {
+            return Err(Error::DataRateNotAllowed { dr, frequency });
+        }
+        Ok(Uplink { channel, dr })
+    }
+
+    /// Checks a downlink uses a downlink channel, DR and TX power of the plan
+    pub fn check_txpk(&self, txpk: &TxPk) -> Result<(), Error> {
+        let frequency = txpk.freq;
+        let is_channel = match &self.downlink_channels {
+            Some(channels) => channels.iter().any(|f| hz(*f) == hz(frequency)),
+            None => self.channel_index(frequency).is_some(),
+        };
+        if !is_channel && hz(frequency) != hz(self.rx2.0) {
+            return Err(Error::FrequencyOutOfPlan(frequency));
+        }
+        if self.downlink_dr(&txpk.datr).is_none() {
+            return Err(Error::DataRateOutOfPlan(txpk.datr.clone()));
+        }
+        let max = self
+            .max_tx_power(frequency)
+            .ok_or(Error::FrequencyOutOfPlan(frequency))?;
+        if txpk.powe > max {
+            return Err(Error::TxPowerTooHigh {
+                powe: txpk.powe,
+                max,
+                frequency,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        push_data::{RxPkV1, CRC},
+        CodingRate, Modulation, StringOrNum,
+    };
+
+    fn rxpk(freq: f64, datr: &str) -> RxPk {
+        RxPk::V1(RxPkV1 {
+            chan: 0,
+            codr: CodingRate::_4_5,
+            data: vec![],
+            datr: datr.parse().unwrap(),
+            freq,
+            lsnr: 7.0,
+            modu: Modulation::LORA,
+            rfch: 0,
+            rssi: -60,
+            rssis: None,
+            size: 0,
+            stat: CRC::OK,
+            tmst: 0,
+            extra: serde_json::Map::new(),
+        })
+    }
+
+    fn txpk(freq: f64, datr: &str, powe: u64) -> TxPk {
+        TxPk {
+            imme: true,
+            tmst: Some(StringOrNum::N(0)),
+            tmms: None,
+            freq,
+            rfch: 0,
+            powe,
+            modu: Modulation::LORA,
+            datr: datr.parse().unwrap(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: 0,
+            data: vec![],
+            ncrc: None,
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn test_us915() {
+        let plan = Plan::new("US915".parse().unwrap());
+        assert_eq!(
+            plan.check_rxpk(&rxpk(903.9, "SF7BW125")),
+            Ok(Uplink { channel: 8, dr: 3 })
+        );
+        assert_eq!(
+            plan.check_rxpk(&rxpk(904.6, "SF8BW500")),
+            Ok(Uplink { channel: 65, dr: 4 })
+        );
+        assert_eq!(
+            plan.check_rxpk(&rxpk(904.6, "SF7BW125")),
+            Err(Error::DataRateNotAllowed {
+                dr: 3,
+                frequency: 904.6
+            })
+        );
+        let sf8bw500 = DataRate::new(SF8, BW500);
+        assert_eq!(plan.uplink_dr(&sf8bw500), Some(4));
+        assert_eq!(plan.downlink_dr(&sf8bw500), Some(12));
+
+        assert_eq!(plan.rx1_frequency(903.5), Ok(926.9));
+        assert_eq!(plan.rx1_frequency(904.6), Ok(923.9));
+        assert_eq!(plan.rx1_dr(0, 0), Ok(10));
+        assert_eq!(plan.rx1_dr(4, 0), Ok(13));
+        assert_eq!(plan.rx1_dr(0, 3), Ok(8));
+        assert_eq!(
+            plan.rx1_dr(0, 4),
+            Err(Error::Rx1DrOffsetOutOfPlan(4, Region::US915))
+        );
+
+        assert_eq!(plan.check_txpk(&txpk(925.1, "SF10BW500", 27)), Ok(()));
+        assert_eq!(
+            plan.check_txpk(&txpk(903.9, "SF10BW500", 27)),
+            Err(Error::FrequencyOutOfPlan(903.9))
+        );
+    }
+
+    #[test]
+    fn test_eu868() {
+        let mut plan = Plan::new(Region::EU868);
+        assert_eq!(
+            plan.check_rxpk(&rxpk(867.1, "SF7BW125")),
+            Err(Error::FrequencyOutOfPlan(867.1))
+        );
+        plan.add_channel(Channel::new(867.1, 0..=5)).unwrap();
+        assert_eq!(
+            plan.check_rxpk(&rxpk(867.1, "SF12BW125")),
+            Ok(Uplink { channel: 3, dr: 0 })
+        );
+        assert!(plan.add_channel(Channel::new(902.3, 0..=5)).is_err());
+
+        assert_eq!(plan.rx1_frequency(867.1), Ok(867.1));
+        assert_eq!(plan.rx1_dr(5, 2), Ok(3));
+        assert_eq!(plan.rx1_dr(1, 2), Ok(0));
+
+        assert_eq!(plan.check_txpk(&txpk(869.525, "SF12BW125", 27)), Ok(()));
+        assert_eq!(plan.sub_band(869.525).unwrap().duty_cycle(), Some(0.1));
+        assert_eq!(plan.sub_band(868.1).unwrap().duty_cycle(), Some(0.01));
+        assert_eq!(plan.sub_band(868.65), None);
+        assert_eq!(plan.dwell_time(), None);
+        assert_eq!(
+            plan.check_txpk(&txpk(868.1, "SF12BW125", 27)),
+            Err(Error::TxPowerTooHigh {
+                powe: 27,
+                max: 16,
+                frequency: 868.1
+            })
+        );
+        assert_eq!(
+            plan.check_txpk(&txpk(868.1, "SF7BW500", 14)),
+            Err(Error::DataRateOutOfPlan(DataRate::new(SF7, BW500)))
+        );
+    }
+}
@@ -0,0 +1,205 @@
+/*
+   Routes uplinks according to the LoRaWAN header of their PHYPayload:
+   data frames by DevAddr, or by the NetID the DevAddr belongs to, and
+   join requests by JoinEUI. The header is read through lorawan::PhyPayload,
+   which never looks at the rest of the frame.
+
+   Targets are generic so the same table can pick an upstream of the mux,
+   a channel fed from server_runtime events, or anything else
+*/
+use crate::{
+    lorawan::{Payload, PhyPayload},
+    push_data::RxPk,
+};
+
+/// The field of the LoRaWAN header an uplink is routed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingKey {
+    /// data frames
+    DevAddr(u32),
+    /// rejoin requests of type 0 and 2
+    NetId(u32),
+    /// join requests and rejoin requests of type 1
+    JoinEui(u64),
+}
+
+impl RoutingKey {
+    /// Reads the key from the header of a PHYPayload, None for proprietary
+    /// frames and frames too short to hold their header
+    pub fn parse(phy_payload: &[u8]) -> Option<RoutingKey> {
+        match PhyPayload::new(phy_payload).ok()?.payload() {
+            Payload::JoinRequest(join) => Some(RoutingKey::JoinEui(join.join_eui())),
+            Payload::Data(data) => Some(RoutingKey::DevAddr(data.dev_addr())),
+            Payload::RejoinRequest(rejoin) => rejoin
+                .net_id()
+                .map(RoutingKey::NetId)
+                .or_else(|| rejoin.join_eui().map(RoutingKey::JoinEui)),
+            Payload::JoinAccept(_) | Payload::Proprietary(_) => None,
+        }
+    }
+}
+
+/// Returns the NetID of the network which allocated a DevAddr, None if the
+/// DevAddr uses the reserved prefix of eight 1 bits
+pub fn net_id(dev_addr: u32) -> Option<u32> {
+    // number of bits of the NwkID for each DevAddr type
+    const NWK_ID_BITS: [u32; 8] = [6, 6, 9, 11, 12, 13, 15, 17];
+    let net_type = dev_addr.leading_ones();
+    let nwk_id_bits = *NWK_ID_BITS.get(net_type as usize)?;
+    // the type prefix is followed by a 0 and the NwkID
+    let prefix_len = net_type + 1;
+    let nwk_id = (dev_addr << prefix_len) >> (32 - nwk_id_bits);
+    Some(net_type << 21 | nwk_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// data frames whose DevAddr starts with the `len` first bits of `prefix`
+    DevAddrPrefix { prefix: u32, len: u8 },
+    /// data frames whose DevAddr was allocated by this network, and rejoin
+    /// requests carrying it
+    NetId(u32),
+    /// join requests whose JoinEUI is in this inclusive range
+    JoinEui { first: u64, last: u64 },
+}
+
+impl Rule {
+    pub fn matches(&self, key: &RoutingKey) -> bool {
+        match (self, key) {
+            (Rule::DevAddrPrefix { prefix, len }, RoutingKey::DevAddr(dev_addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - (*len).min(32) as u32)
+                    .unwrap_or(0);
+                dev_addr & mask == prefix & mask
+            }
+            (Rule::NetId(id), RoutingKey::DevAddr(dev_addr)) => net_id(*dev_addr) == Some(*id),
+            (Rule::NetId(id), RoutingKey::NetId(net_id)) => id == net_id,
+            (Rule::JoinEui { first, last }, RoutingKey::JoinEui(eui)) => {
+                (first..=last).contains(&eui)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Routes are tried in the order they were added and the first one to match
+/// wins; uplinks matching none of them take the default route, if any
+#[derive(Debug, Clone)]
+pub struct RoutingTable<T> {
+    routes: Vec<(Rule, T)>,
+    default: Option<T>,
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> RoutingTable<T> {
+        RoutingTable {
+            routes: Vec::new(),
+            default: None,
+        }
+    }
+}
+
+impl<T> RoutingTable<T> {
+    pub fn new() -> RoutingTable<T> {
+        RoutingTable::default()
+    }
+
+    pub fn with_default(default: T) -> RoutingTable<T> {
+        RoutingTable {
+            routes: Vec::new(),
+            default: Some(default),
+        }
+    }
+
+    pub fn add(&mut self, rule: Rule, target: T) {
+        self.routes.push((rule, target));
+    }
+
+    pub fn set_default(&mut self, target: Option<T>) {
+        self.default = target;
+    }
+
+    pub fn route(&self, phy_payload: &[u8]) -> Option<&T> {
+        RoutingKey::parse(phy_payload)
+            .and_then(|key| {
+                self.routes
+                    .iter()
+                    .find(|(rule, _)| rule.matches(&key))
+                    .map(|(_, target)| target)
+            })
+            .or(self.default.as_ref())
+    }
+
+    pub fn route_rxpk(&self, rxpk: &RxPk) -> Option<&T> {
+        self.route(rxpk.get_data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_up(dev_addr: u32) -> Vec<u8> {
+        let mut frame = vec![0x40];
+        frame.extend_from_slice(&dev_addr.to_le_bytes());
+        frame.extend_from_slice(&[0, 1, 0, 1, 0xde, 0xad, 0xbe, 0xef]);
+        frame
+    }
+
+    fn join_request(join_eui: u64) -> Vec<u8> {
+        let mut frame = vec![0x00];
+        frame.extend_from_slice(&join_eui.to_le_bytes());
+        frame.extend_from_slice(&[0x11; 14]);
+        frame
+    }
+
+    #[test]
+    fn test_net_id() {
+        assert_eq!(net_id(0x7800_0001), Some(0x00_00_3c));
+        assert_eq!(net_id(0xe05a_1234), Some(0x60_00_2d));
+        assert_eq!(net_id(0xfe00_0000), Some(0xe0_00_00));
+        assert_eq!(net_id(0xff00_0000), None);
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let mut table = RoutingTable::with_default("default");
+        table.add(
+            Rule::DevAddrPrefix {
+                prefix: 0x2601_0000,
+                len: 16,
+            },
+            "tenant",
+        );
+        table.add(Rule::NetId(0x60_00_2d), "roaming");
+        table.add(
+            Rule::JoinEui {
+                first: 0x70b3_d57e_d000_0000,
+                last: 0x70b3_d57e_d0ff_ffff,
+            },
+            "join server",
+        );
+
+        assert_eq!(table.route(&data_up(0x2601_abcd)), Some(&"tenant"));
+        assert_eq!(table.route(&data_up(0xe05a_0001)), Some(&"roaming"));
+        assert_eq!(table.route(&data_up(0x2602_abcd)), Some(&"default"));
+        assert_eq!(
+            table.route(&join_request(0x70b3_d57e_d012_3456)),
+            Some(&"join server")
+        );
+        assert_eq!(
+            table.route(&join_request(0x70b3_d57e_d112_3456)),
+            Some(&"default")
+        );
+        // rejoin request of type 0, carrying the NetID
+        let mut rejoin = vec![0xc0, 0x00, 0x2d, 0x00, 0x60];
+        rejoin.extend_from_slice(&[0x11; 14]);
+        assert_eq!(table.route(&rejoin), Some(&"roaming"));
+        // proprietary and truncated frames take the default route
+        assert_eq!(table.route(&[0xe0, 1, 2]), Some(&"default"));
+        assert_eq!(table.route(&data_up(0x2601_abcd)[..6]), Some(&"default"));
+
+        table.set_default(None);
+        assert_eq!(table.route(&[]), None);
+    }
+}
@@ -0,0 +1,241 @@
+/*
+   Builds the txpk answering an uplink in its RX1 or RX2 window, following
+   the LoRaWAN regional parameters of a plan:
+
+   RX1 opens RX1Delay after the end of the uplink, on the frequency and DR
+   derived from the uplink. RX2 opens one second later, on the fixed
+   frequency and DR of the region. Join accepts use the longer
+   JOIN_ACCEPT_DELAY1 and JOIN_ACCEPT_DELAY2 instead.
+
+   Windows are scheduled on the concentrator counter, which wraps around
+   every 2^32 us (about 71 minutes).
+*/
+use crate::{
+    pull_resp::TxPk,
+    push_data::RxPk,
+    region::{Error, Plan},
+    CodingRate, ConcentratorTimestamp, Modulation,
+};
+use std::time::Duration;
+
+pub const RECEIVE_DELAY1: Duration = Duration::from_secs(1);
+pub const JOIN_ACCEPT_DELAY1: Duration = Duration::from_secs(5);
+pub const JOIN_ACCEPT_DELAY2: Duration = Duration::from_secs(6);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// RX1Delay of the device, RX2 opens one second later
+    pub rx1_delay: Duration,
+    pub rx1_dr_offset: usize,
+    /// RX2 frequency in MHz and DR, None for the defaults of the plan
+    pub rx2: Option<(f64, usize)>,
+    /// TX power in dBm, None for the highest the plan allows on the frequency
+    pub tx_power: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            rx1_delay: RECEIVE_DELAY1,
+            rx1_dr_offset: 0,
+            rx2: None,
+            tx_power: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Windows {
+    pub rx1: TxPk,
+    pub rx2: TxPk,
+}
+
+impl Windows {
+    /// Answers a data uplink after the RX1Delay of the settings
+    pub fn new(
+        plan: &Plan,
+        settings: &Settings,
+        uplink: &RxPk,
+        payload: Vec<u8>,
+    ) -> Result<Windows, Error> {
+        build(plan, settings, uplink, payload, settings.rx1_delay)
+    }
+
+    /// Answers a join request, with the join accept delays of the region.
+    /// The device has not been configured yet, so only the TX power of the
+    /// settings applies: RX1 uses no DR offset and RX2 the defaults of the plan
+    pub fn join_accept(
+        plan: &Plan,
+        settings: &Settings,
+        uplink: &RxPk,
+        payload: Vec<u8>,
+    ) -> Result<Windows, Error> {
+        let settings = Settings {
+            rx1_dr_offset: 0,
+            rx2: None,
+            ..settings.clone()
+        };
+        build(plan, &settings, uplink, payload, JOIN_ACCEPT_DELAY1)
+    }
+}
+
+fn build(
+    plan: &Plan,
+    settings: &Settings,
+    uplink: &RxPk,
+    payload: Vec<u8>,
+    rx1_delay: Duration,
+) -> Result<Windows, Error> {
+    let received = plan.check_rxpk(uplink)?;
+    let rx1_frequency = plan.rx1_frequency(*uplink.get_frequency())?;
+    let rx1_dr = plan.rx1_dr(received.dr, settings.rx1_dr_offset)?;
+    let (rx2_frequency, rx2_dr) = settings.rx2.unwrap_or_else(|| plan.rx2());
+
+    let tmst = uplink.get_timestamp();
+    let rx1 = txpk(
+        plan,
+        settings,
+        tmst + rx1_delay,
+        rx1_frequency,
+        rx1_dr,
+        payload.clone(),
+    )?;
+    let rx2 = txpk(
+        plan,
+        settings,
+        tmst + rx1_delay + Duration::from_secs(1),
+        rx2_frequency,
+        rx2_dr,
+        payload,
+    )?;
+    Ok(Windows { rx1, rx2 })
+}
+
+fn txpk(
+    plan: &Plan,
+    settings: &Settings,
+    tmst: ConcentratorTimestamp,
+    freq: f64,
+    dr: usize,
+    data: Vec<u8>,
+) -> Result<TxPk, Error> {
+    let datr = plan.data_rate(dr).ok_or(Error::DataRateNotAllowed {
+        dr,
+        frequency: freq,
+    })?;
+    let powe = match settings.tx_power {
+        Some(powe) => powe,
+        None => plan
+            .max_tx_power(freq)
+            .ok_or(Error::FrequencyOutOfPlan(freq))?,
+    };
+    let txpk = TxPk {
+        imme: false,
+        tmst: Some(tmst.into()),
+        tmms: None,
+        freq,
+        rfch: 0,
+        powe,
+        modu: Modulation::LORA,
+        datr,
+        codr: CodingRate::_4_5,
+        fdev: None,
+        // devices listen with inverted polarity so they do not hear each other
+        ipol: true,
+        prea: None,
+        size: data.len() as u64,
+        data,
+        // LoRaWAN downlinks carry no PHY CRC
+        ncrc: Some(true),
+        nhdr: None,
+        extra: serde_json::Map::new(),
+    };
+    plan.check_txpk(&txpk)?;
+    Ok(txpk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        push_data::{RxPkV1, CRC},
+        region::Region,
+        StringOrNum,
+    };
+
+    fn rxpk(tmst: u32, freq: f64, datr: &str) -> RxPk {
+        RxPk::V1(RxPkV1 {
+            chan: 0,
+            codr: CodingRate::_4_5,
+            data: vec![0x40; 13],
+            datr: datr.parse().unwrap(),
+            freq,
+            lsnr: 7.0,
+            modu: Modulation::LORA,
+            rfch: 0,
+            rssi: -60,
+            rssis: None,
+            size: 13,
+            stat: CRC::OK,
+            tmst,
+            extra: serde_json::Map::new(),
+        })
+    }
+
+    #[test]
+    fn test_eu868_windows() {
+        let plan = Plan::new(Region::EU868);
+        let settings = Settings {
+            rx1_dr_offset: 1,
+            tx_power: Some(14),
+            ..Default::default()
+        };
+        let uplink = rxpk(u32::MAX - 499_999, 868.3, "SF9BW125");
+        let windows = Windows::new(&plan, &settings, &uplink, vec![1, 2, 3]).unwrap();
+
+        // the counter wraps around between the uplink and its windows
+        assert_eq!(windows.rx1.tmst, Some(StringOrNum::N(500_000)));
+        assert_eq!(windows.rx1.freq, 868.3);
+        assert_eq!(windows.rx1.datr, "SF10BW125".parse().unwrap());
+        assert!(windows.rx1.ipol);
+        assert_eq!(windows.rx1.size, 3);
+        assert_eq!(windows.rx2.tmst, Some(StringOrNum::N(1_500_000)));
+        assert_eq!(windows.rx2.freq, 869.525);
+        assert_eq!(windows.rx2.datr, "SF12BW125".parse().unwrap());
+
+        let join = Windows::join_accept(&plan, &Settings::default(), &uplink, vec![]).unwrap();
+        assert_eq!(join.rx1.tmst, Some(StringOrNum::N(4_500_000)));
+        assert_eq!(join.rx2.tmst, Some(StringOrNum::N(5_500_000)));
+        // the DR offset and RX2 of a device do not apply to its join accept
+        let configured = Settings {
+            rx2: Some((869.525, 3)),
+            ..settings
+        };
+        let join_configured = Windows::join_accept(&plan, &configured, &uplink, vec![]).unwrap();
+        assert_eq!(join_configured.rx1.datr, "SF9BW125".parse().unwrap());
+        assert_eq!(join_configured.rx2.datr, "SF12BW125".parse().unwrap());
+        // the plan caps the default power of each window
+        assert_eq!(join.rx1.powe, 16);
+        assert_eq!(join.rx2.powe, 27);
+    }
+
+    #[test]
+    fn test_us915_windows() {
+        let plan = Plan::new(Region::US915);
+        let uplink = rxpk(1_000, 903.5, "SF7BW125");
+        let windows = Windows::new(&plan, &Settings::default(), &uplink, vec![]).unwrap();
+        assert_eq!(windows.rx1.freq, 926.9);
+        assert_eq!(windows.rx1.datr, "SF7BW500".parse().unwrap());
+        assert_eq!(windows.rx2.freq, 923.3);
+        assert_eq!(windows.rx2.datr, "SF12BW500".parse().unwrap());
+
+        let too_loud = Settings {
+            tx_power: Some(36),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Windows::new(&plan, &too_loud, &uplink, vec![]),
+            Err(Error::TxPowerTooHigh { .. })
+        ));
+    }
+}
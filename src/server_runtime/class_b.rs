@@ -0,0 +1,269 @@
+/*
+   Class B on the server side: the runtime emits a beacon on every GPS
+   locked gateway each beacon period, and sends downlinks in the ping slots
+   of devices.
+
+   The ping slots of a device move from one beacon period to the next, by
+   an offset derived from the beacon time and the DevAddr:
+
+   Rand       = aes128_encrypt(16 zero bytes, BeaconTime | DevAddr | zero padding)
+   pingOffset = (Rand[0] + Rand[1] * 256) mod pingPeriod
+
+   with pingPeriod = 2^(5 + periodicity) slots of 30 ms. Slot N of the
+   period opens BEACON_RESERVED + (pingOffset + N * pingPeriod) * 30 ms
+   after the beacon.
+*/
+use super::{ClientTx, Error, Result};
+use crate::{
+    class_b::{Beacon, BEACON_PERIOD, BEACON_RESERVED, PING_SLOTS, PING_SLOT_LEN},
+    pull_resp::TxPk,
+    push_data::Stat,
+    region::Plan,
+    time::{GpsTime, LeapSeconds},
+    tx_ack::Error as TxAckError,
+    MacAddress,
+};
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+// a ping slot colliding with a beacon is moved to the following slots
+const MAX_PING_SLOT_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct ClassBConfig {
+    pub plan: Plan,
+    /// TX power of beacons in dBm, None for the highest the plan allows
+    pub tx_power: Option<u64>,
+    /// how long before going on the air beacons and ping slot downlinks
+    /// are sent to the gateway
+    pub lead: Duration,
+}
+
+impl ClassBConfig {
+    pub fn new(plan: Plan) -> ClassBConfig {
+        ClassBConfig {
+            plan,
+            tx_power: None,
+            lead: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Ping slots of a device, as set by its PingSlotInfoReq
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingSlots {
+    dev_addr: u32,
+    periodicity: u8,
+    channel: Option<(f64, usize)>,
+}
+
+impl PingSlots {
+    /// Slots every 2^periodicity seconds, None for a periodicity above 7
+    pub fn new(dev_addr: u32, periodicity: u8) -> Option<PingSlots> {
+        (periodicity <= 7).then_some(PingSlots {
+            dev_addr,
+            periodicity,
+            channel: None,
+        })
+    }
+
+    /// Frequency in MHz and DR set by a PingSlotChannelReq, instead of the
+    /// defaults of the region
+    pub fn with_channel(self, frequency: f64, dr: usize) -> PingSlots {
+        PingSlots {
+            channel: Some((frequency, dr)),
+            ..self
+        }
+    }
+
+    // in slots of 30 ms
+    fn ping_period(&self) -> u32 {
+        1 << (5 + self.periodicity)
+    }
+
+    /// Offset of the first slot of the device in a beacon period
+    pub fn offset(&self, beacon: &Beacon) -> u32 {
+        let beacon_time = (beacon.time().as_millis() / 1_000) as u32;
+        let mut block = [0; 16];
+        block[..4].copy_from_slice(&beacon_time.to_le_bytes());
+        block[4..8].copy_from_slice(&self.dev_addr.to_le_bytes());
+        let mut block = GenericArray::from(block);
+        Aes128::new(&GenericArray::from([0; 16])).encrypt_block(&mut block);
+        (block[0] as u32 + block[1] as u32 * 256) % self.ping_period()
+    }
+
+    /// Opening of every slot of the device in a beacon period
+    pub fn slots(&self, beacon: &Beacon) -> Vec<GpsTime> {
+        let period = self.ping_period();
+        let first = beacon.time().as_millis()
+            + BEACON_RESERVED.as_millis() as u64
+            + (self.offset(beacon) as u64) * PING_SLOT_LEN.as_millis() as u64;
+        (0..PING_SLOTS / period)
+            .map(|n| {
+                let slot = (n * period) as u64 * PING_SLOT_LEN.as_millis() as u64;
+                GpsTime::from_millis(first + slot)
+            })
+            .collect()
+    }
+
+    /// First slot of the device opening after a GPS time, with its beacon
+    pub fn next_after(&self, time: GpsTime) -> (Beacon, GpsTime) {
+        let beacon = Beacon::at(time);
+        match self.slots(&beacon).into_iter().find(|slot| *slot > time) {
+            Some(slot) => (beacon, slot),
+            None => {
+                let beacon = Beacon::after(time);
+                // a period always holds at least one slot
+                (beacon, self.slots(&beacon)[0])
+            }
+        }
+    }
+}
+
+/// Latest status of every gateway, for the location in its beacons
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Stats(Arc<Mutex<HashMap<MacAddress, Stat>>>);
+
+impl Stats {
+    fn lock(&self) -> MutexGuard<'_, HashMap<MacAddress, Stat>> {
+        // every update leaves the map consistent, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, mac: MacAddress, stat: &Stat) {
+        self.lock().insert(mac, stat.clone());
+    }
+
+    fn get(&self, mac: &MacAddress) -> Option<Stat> {
+        self.lock().get(mac).cloned()
+    }
+}
+
+fn gps_now(after: Duration) -> Result<GpsTime> {
+    let at = SystemTime::now() + after;
+    GpsTime::from_utc(at, &LeapSeconds::default()).ok_or(Error::UnschedulableTime(at))
+}
+
+impl ClientTx {
+    /// Sends a downlink in the next ping slot of a device which the gateway
+    /// can still make, on the default ping slot channel of the region
+    /// unless the slots have their own. Returns the slot it was sent in.
+    pub async fn send_ping_slot(
+        &mut self,
+        mut txpk: TxPk,
+        mac: MacAddress,
+        slots: &PingSlots,
+        timeout: Option<Duration>,
+    ) -> Result<GpsTime> {
+        let config = self.class_b.clone().ok_or(Error::ClassBDisabled)?;
+        if !self
+            .clocks
+            .get(&mac)
+            .is_some_and(|clock| clock.gps_locked())
+        {
+            return Err(Error::GpsUnlocked(mac));
+        }
+        let mut after = gps_now(config.lead)?;
+        for _ in 0..MAX_PING_SLOT_ATTEMPTS {
+            let (beacon, slot) = slots.next_after(after);
+            let (frequency, dr) = match slots.channel {
+                Some(channel) => channel,
+                None => beacon.ping_slot_channel(config.plan.region(), slots.dev_addr)?,
+            };
+            txpk.freq = frequency;
+            txpk.datr = config
+                .plan
+                .data_rate(dr)
+                .ok_or(crate::region::Error::DataRateNotAllowed { dr, frequency })?;
+            txpk.set_gps_time(slot);
+            match self.send(txpk.clone(), mac, timeout).await {
+                Err(Error::Ack(TxAckError::CollisionBeacon)) => after = slot,
+                Err(Error::Ack(TxAckError::GpsUnlocked)) => {
+                    self.clocks.set_gps_unlocked(&mac);
+                    return Err(Error::GpsUnlocked(mac));
+                }
+                result => return result.map(|_| slot),
+            }
+        }
+        Err(TxAckError::CollisionBeacon.into())
+    }
+}
+
+/// Emits a beacon on every GPS locked gateway, each beacon period
+pub(crate) async fn run_beacons(config: ClassBConfig, tx: ClientTx, stats: Stats) {
+    let leap_seconds = LeapSeconds::default();
+    loop {
+        let beacon = match gps_now(config.lead) {
+            Ok(now) => Beacon::after(now),
+            Err(_) => {
+                tokio::time::sleep(BEACON_PERIOD).await;
+                continue;
+            }
+        };
+        let send_at = beacon.time().to_utc(&leap_seconds) - config.lead;
+        if let Ok(wait) = send_at.duration_since(SystemTime::now()) {
+            tokio::time::sleep(wait).await;
+        }
+        for mac in tx.clocks.gps_locked() {
+            let beacon = match stats.get(&mac) {
+                Some(stat) => beacon.with_stat(&stat),
+                None => beacon,
+            };
+            let txpk = match beacon.txpk(&config.plan, config.tx_power) {
+                Ok(txpk) => txpk,
+                Err(e) => {
+                    warn!("Unable to build beacon: {}", e);
+                    continue;
+                }
+            };
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                match tx.send(txpk, mac, Some(BEACON_PERIOD)).await {
+                    Ok(()) => (),
+                    Err(Error::Ack(TxAckError::GpsUnlocked)) => {
+                        warn!("Gateway {} lost its GPS lock, beacons paused", mac);
+                        tx.clocks.set_gps_unlocked(&mac);
+                    }
+                    Err(e) => warn!("Beacon on gateway {} failed: {}", mac, e),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_slots() {
+        let beacon = Beacon::at(GpsTime::from_millis(1_300_000_000_000));
+        let every_second = PingSlots::new(0x2601_1CF1, 0).unwrap();
+        let slots = every_second.slots(&beacon);
+        assert_eq!(slots.len(), 128);
+        let offset = every_second.offset(&beacon);
+        assert!(offset < 32);
+        assert_eq!(
+            slots[0].as_millis() - beacon.time().as_millis(),
+            2_120 + offset as u64 * 30
+        );
+        assert_eq!(slots[1].as_millis() - slots[0].as_millis(), 960);
+        // the last slot ends before the guard time of the next beacon
+        assert!(slots[127].as_millis() + 30 <= beacon.time().as_millis() + 125_000);
+
+        let every_128s = PingSlots::new(0x2601_1CF1, 7).unwrap();
+        assert_eq!(every_128s.slots(&beacon).len(), 1);
+        let (next, slot) = every_128s.next_after(every_128s.slots(&beacon)[0]);
+        assert_eq!(next, Beacon::after(beacon.time()));
+        assert_eq!(slot, every_128s.slots(&next)[0]);
+        assert_eq!(PingSlots::new(0, 8), None);
+    }
+}
@@ -0,0 +1,294 @@
+/*
+   Estimates, for each gateway, how its tmst counter maps onto the wall
+   clock of the server, from the tmst of the uplinks it forwards and the
+   time they reach us.
+
+   Network and forwarding delays only ever make an uplink look late, so the
+   offset follows the lower envelope of the samples: the uplink which
+   arrived earliest relative to its tmst is the closest to the true offset,
+   and the spread of the others above it bounds the uncertainty. The drift
+   of the counter is the slope of that envelope, between the earliest
+   arrivals of the older and of the newer half of the samples.
+*/
+use crate::{
+    push_data::{RxPk, RxPkV2},
+    ConcentratorTimestamp, MacAddress,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_SAMPLES: usize = 64;
+// older samples no longer tell much about the current drift
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(10 * 60);
+// samples must span this long before the drift stands out from the jitter
+const MIN_DRIFT_SPAN: Duration = Duration::from_secs(60);
+// tmst and the wall clock disagreeing by more than this means the packet
+// forwarder restarted, or uplinks were too far apart to unwrap tmst
+const MAX_DISAGREEMENT: Duration = Duration::from_secs(5);
+// tmst is ambiguous more than half a wrap away from the current counter,
+// keep a margin for the time it takes to get the downlink there
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    // microseconds since the UNIX epoch
+    wall: i64,
+    tmst: ConcentratorTimestamp,
+    // tmst unwrapped against the previous samples
+    unwrapped: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// how much faster the counter runs than the server clock, in parts per million
+    pub drift_ppm: f64,
+    /// spread of the delays of the samples, downlinks may go out this much early
+    pub uncertainty: Duration,
+    pub samples: usize,
+    /// arrival of the newest sample
+    pub updated: SystemTime,
+}
+
+/// Mapping between the tmst counter of a gateway and the wall clock
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+    gps_locked: bool,
+}
+
+fn micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
+
+impl ClockSync {
+    pub(crate) fn record(&mut self, received: SystemTime, rxpk: &RxPk) {
+        // buffered by the gateway during an outage, it may have been
+        // received minutes before it reached us
+        if let RxPk::V2(RxPkV2 {
+            delayed: Some(true),
+            ..
+        }) = rxpk
+        {
+            return;
+        }
+        let wall = micros(received);
+        let tmst = rxpk.get_timestamp();
+        let unwrapped = match self.samples.back() {
+            Some(last) => {
+                let unwrapped = last.unwrapped + tmst.signed_duration_since(last.tmst);
+                let disagreement = (unwrapped - last.unwrapped) - (wall - last.wall);
+                if disagreement.unsigned_abs() > MAX_DISAGREEMENT.as_micros() as u64 {
+                    self.samples.clear();
+                }
+                unwrapped
+            }
+            None => tmst.as_u32() as i64,
+        };
+        self.samples.push_back(Sample {
+            wall,
+            tmst,
+            unwrapped,
+        });
+        while self.samples.len() > MAX_SAMPLES
+            || self
+                .samples
+                .front()
+                .is_some_and(|oldest| wall - oldest.wall > MAX_SAMPLE_AGE.as_micros() as i64)
+        {
+            self.samples.pop_front();
+        }
+        // only gateways with a GPS lock stamp uplinks with tmms
+        self.gps_locked = rxpk.get_gps_time().is_some();
+    }
+
+    /// Whether the latest uplink carried GPS time, so the gateway accepts
+    /// downlinks on tmms
+    pub fn gps_locked(&self) -> bool {
+        self.gps_locked
+    }
+
+    // wall clock per tmst microsecond, offset of the lower envelope and
+    // spread above it, relative to the oldest sample
+    fn fit(&self) -> Option<(f64, f64, f64)> {
+        let oldest = self.samples.front()?;
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .map(|s| {
+                (
+                    (s.unwrapped - oldest.unwrapped) as f64,
+                    (s.wall - oldest.wall) as f64,
+                )
+            })
+            .collect();
+        let earliest = |points: &[(f64, f64)]| {
+            points
+                .iter()
+                .copied()
+                .min_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+        };
+        let (older, newer) = points.split_at(points.len() / 2);
+        let slope = match (earliest(older), earliest(newer)) {
+            (Some(a), Some(b)) if b.0 - a.0 >= MIN_DRIFT_SPAN.as_micros() as f64 => {
+                (b.1 - a.1) / (b.0 - a.0)
+            }
+            _ => 1.0,
+        };
+        let residuals = points.iter().map(|(x, y)| y - slope * x);
+        let (min, max) = residuals.fold((f64::MAX, f64::MIN), |(min, max), r| {
+            (min.min(r), max.max(r))
+        });
+        Some((slope, min, max - min))
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        let (slope, _, spread) = self.fit()?;
+        let newest = self.samples.back()?;
+        Some(ClockEstimate {
+            drift_ppm: (1.0 / slope - 1.0) * 1e6,
+            uncertainty: Duration::from_micros(spread as u64),
+            samples: self.samples.len(),
+            updated: UNIX_EPOCH + Duration::from_micros(newest.wall as u64),
+        })
+    }
+
+    /// Value of the counter at a wall clock time, None without samples or
+    /// when the time is too far from the newest sample for tmst to tell
+    pub fn tmst_at(&self, at: SystemTime) -> Option<ConcentratorTimestamp> {
+        let (slope, offset, _) = self.fit()?;
+        let (oldest, newest) = (self.samples.front()?, self.samples.back()?);
+        let at = micros(at);
+        if (at - newest.wall).unsigned_abs() > MAX_SCHEDULE_AHEAD.as_micros() as u64 {
+            return None;
+        }
+        let elapsed = ((at - oldest.wall) as f64 - offset) / slope;
+        let unwrapped = oldest.unwrapped + elapsed.round() as i64;
+        Some(ConcentratorTimestamp::new(
+            unwrapped.rem_euclid(1 << 32) as u32
+        ))
+    }
+
+    /// Wall clock time at which the counter reaches a value, taken within
+    /// half a wrap of the newest sample
+    pub fn time_at(&self, tmst: ConcentratorTimestamp) -> Option<SystemTime> {
+        let (slope, offset, _) = self.fit()?;
+        let (oldest, newest) = (self.samples.front()?, self.samples.back()?);
+        let unwrapped = newest.unwrapped + tmst.signed_duration_since(newest.tmst);
+        let wall = oldest.wall + (offset + slope * (unwrapped - oldest.unwrapped) as f64) as i64;
+        Some(UNIX_EPOCH + Duration::from_micros(wall.max(0) as u64))
+    }
+}
+
+/// Clock sync of every gateway, shared by the runtime halves
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clocks(Arc<Mutex<HashMap<MacAddress, ClockSync>>>);
+
+impl Clocks {
+    fn lock(&self) -> MutexGuard<'_, HashMap<MacAddress, ClockSync>> {
+        // every update leaves the map consistent, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, mac: MacAddress, received: SystemTime, rxpk: &RxPk) {
+        self.lock().entry(mac).or_default().record(received, rxpk);
+    }
+
+    pub fn get(&self, mac: &MacAddress) -> Option<ClockSync> {
+        self.lock().get(mac).cloned()
+    }
+
+    /// Gateways whose latest uplink carried GPS time
+    pub fn gps_locked(&self) -> Vec<MacAddress> {
+        self.lock()
+            .iter()
+            .filter(|(_, clock)| clock.gps_locked)
+            .map(|(mac, _)| *mac)
+            .collect()
+    }
+
+    /// Until its next uplink with GPS time, after the gateway refused a
+    /// downlink for lack of GPS lock
+    pub fn set_gps_unlocked(&self, mac: &MacAddress) {
+        if let Some(clock) = self.lock().get_mut(mac) {
+            clock.gps_locked = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        push_data::{RxPkV1, CRC},
+        CodingRate, Modulation,
+    };
+
+    fn rxpk(tmst: u32) -> RxPk {
+        RxPk::V1(RxPkV1 {
+            chan: 0,
+            codr: CodingRate::_4_5,
+            data: vec![],
+            datr: "SF7BW125".parse().unwrap(),
+            freq: 868.1,
+            lsnr: 7.0,
+            modu: Modulation::LORA,
+            rfch: 0,
+            rssi: -60,
+            rssis: None,
+            size: 0,
+            stat: CRC::OK,
+            tmst,
+            extra: serde_json::Map::new(),
+        })
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mut clock = ClockSync::default();
+        assert_eq!(clock.tmst_at(start), None);
+
+        // the counter runs 10 ppm fast and wraps; uplinks take 20 to 50 ms to arrive
+        let first_tmst = u32::MAX - 100_000_000;
+        for (i, delay) in [20, 50, 35, 20, 42, 20].iter().enumerate() {
+            let elapsed = i as u64 * 30_000_000;
+            let tmst = first_tmst.wrapping_add((elapsed + elapsed / 100_000) as u32);
+            let received = start + Duration::from_micros(elapsed + delay * 1_000);
+            clock.record(received, &rxpk(tmst));
+        }
+
+        let estimate = clock.estimate().unwrap();
+        assert_eq!(estimate.samples, 6);
+        assert!((estimate.drift_ppm - 10.0).abs() < 1.0);
+        assert!(estimate.uncertainty <= Duration::from_millis(30));
+
+        // the uplinks arriving 20 ms late anchor the estimate
+        let at = start + Duration::from_secs(200) + Duration::from_millis(20);
+        let expected = first_tmst.wrapping_add(200_002_000);
+        let tmst = clock.tmst_at(at).unwrap();
+        assert!(tmst.signed_duration_since(expected.into()).abs() < 100);
+        let back = clock.time_at(tmst).unwrap();
+        assert!(back.max(at).duration_since(back.min(at)).unwrap() < Duration::from_micros(100));
+        assert_eq!(clock.tmst_at(start + Duration::from_secs(3600)), None);
+        assert!(!clock.gps_locked());
+
+        // an uplink replayed after an outage is not a sample
+        let delayed: RxPk = serde_json::from_str(&format!(
+            r#"{{"aesk":0,"brd":0,"codr":"4/5","data":"","datr":"SF7BW125","freq":868.1,"jver":2,"modu":"LORA","rsig":[{{"ant":0,"chan":0,"lsnr":7.0,"rssic":-60}}],"size":0,"stat":1,"tmst":{},"delayed":true}}"#,
+            first_tmst
+        ))
+        .unwrap();
+        clock.record(start + Duration::from_secs(170), &delayed);
+        assert_eq!(clock.estimate().unwrap().samples, 6);
+        assert_eq!(clock.tmst_at(at), Some(tmst));
+
+        // the packet forwarder restarted
+        clock.record(start + Duration::from_secs(160), &rxpk(1_000));
+        assert_eq!(clock.estimate().unwrap().samples, 1);
+    }
+}
@@ -1,4 +1,6 @@
 use super::{Event, InternalEvent};
+use crate::MacAddress;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 
@@ -24,6 +26,22 @@ pub enum Error {
     AckRecv,
     #[error("error sending ACK")]
     AckSend,
+    #[error("no uplink from gateway {0} to sync its clock with")]
+    NoClockSync(MacAddress),
+    #[error("{0:?} is too far from the clock of the gateway to schedule")]
+    UnschedulableTime(SystemTime),
+    #[error("regulatory limit: {0}")]
+    Limit(#[from] super::LimitError),
+    #[error("Class B needs Config::class_b")]
+    ClassBDisabled,
+    #[error("gateway {0} has no GPS lock")]
+    GpsUnlocked(MacAddress),
+    #[error("region error: {0}")]
+    Region(#[from] crate::region::Error),
+    #[error("downlink cancelled")]
+    Cancelled,
+    #[error("downlink pre-empted by one of a higher priority")]
+    Preempted,
 }
 
 impl From<tokio::time::error::Elapsed> for Error {
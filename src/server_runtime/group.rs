@@ -0,0 +1,216 @@
+/*
+   Sends the same downlink from a set of gateways at once, as multicast
+   sessions of Class C devices and FUOTA do. Every gateway gets its own
+   PULL_RESP and token, and all of them are handed over to the runtime
+   before waiting for the first ACK, so the gateways share one timeout
+   instead of adding theirs up.
+*/
+use super::{ClientTx, Error, Result, UdpRuntime};
+use crate::{pull_resp::TxPk, tx_ack, MacAddress};
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// What came of the downlink on one gateway of the group
+#[derive(Debug)]
+pub enum Outcome {
+    Acked,
+    Rejected(tx_ack::Error),
+    NoClient,
+    TimedOut,
+    /// refused before reaching the gateway, eg: by the limiter
+    Failed(Error),
+}
+
+impl Outcome {
+    pub fn is_acked(&self) -> bool {
+        matches!(self, Outcome::Acked)
+    }
+}
+
+impl From<Result> for Outcome {
+    fn from(result: Result) -> Outcome {
+        match result {
+            Ok(()) => Outcome::Acked,
+            Err(Error::Ack(e)) => Outcome::Rejected(e),
+            Err(Error::UnknownMac) => Outcome::NoClient,
+            Err(Error::SendTimeout) => Outcome::TimedOut,
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+}
+
+impl ClientTx {
+    /// Sends a packet from every gateway of a set, returning what came of
+    /// it on each, in the order of the set. The packet is cancelled on the
+    /// gateways which did not ACK it by the timeout
+    pub async fn send_to_group(
+        &mut self,
+        txpk: TxPk,
+        macs: &[MacAddress],
+        timeout: Option<Duration>,
+    ) -> Vec<(MacAddress, Outcome)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut submitted = Vec::with_capacity(macs.len());
+        for mac in macs {
+            let downlink = self.prepare_downlink(Some(txpk.clone()), *mac);
+            submitted.push((*mac, downlink.submit().await));
+        }
+
+        let mut outcomes = Vec::with_capacity(macs.len());
+        for (mac, pending) in submitted {
+            let result = match (pending, deadline) {
+                (Ok(pending), Some(deadline)) => {
+                    let handle = pending.cancel_handle();
+                    match timeout_at(deadline, pending.receive()).await {
+                        Ok(result) => result,
+                        // withdrawn, so it does not go out after the deadline
+                        Err(_) => handle.cancel().await.and(Err(Error::SendTimeout)),
+                    }
+                }
+                (Ok(pending), None) => pending.receive().await,
+                (Err(e), _) => Err(e),
+            };
+            outcomes.push((mac, result.into()));
+        }
+        outcomes
+    }
+}
+
+impl UdpRuntime {
+    pub async fn send_to_group(
+        &mut self,
+        txpk: TxPk,
+        macs: &[MacAddress],
+        timeout: Option<Duration>,
+    ) -> Vec<(MacAddress, Outcome)> {
+        self.tx.send_to_group(txpk, macs, timeout).await
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{client_runtime, CodingRate, DataRate, Down, Modulation, Packet, StringOrNum};
+    use std::net::SocketAddr;
+
+    fn txpk() -> TxPk {
+        TxPk {
+            imme: true,
+            tmst: Some(StringOrNum::N(0)),
+            tmms: None,
+            freq: 869.525,
+            rfch: 0,
+            powe: 14,
+            modu: Modulation::LORA,
+            datr: DataRate::default(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: 2,
+            data: vec![1, 2],
+            ncrc: None,
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    // a gateway which answers every downlink with `answer`, or never does
+    async fn gateway(
+        mac: [u8; 8],
+        port: u16,
+        server: SocketAddr,
+        answer: Option<std::result::Result<(), tx_ack::Error>>,
+    ) -> MacAddress {
+        let local = SocketAddr::from(([127, 0, 0, 1], port));
+        let gateway = client_runtime::UdpRuntime::new(mac, local, server)
+            .await
+            .unwrap();
+        let (mut downlinks, uplinks) = (gateway.subscribe(), gateway.publish_to());
+        gateway.run().await.unwrap();
+        let mac = MacAddress::new(&mac);
+        tokio::spawn(async move {
+            while let Ok(packet) = downlinks.recv().await {
+                if let Packet::Down(Down::PullResp(packet)) = packet {
+                    let ack = match answer {
+                        Some(Ok(())) => packet.into_ack_for_gateway(mac),
+                        Some(Err(e)) => packet.into_nack_with_error_for_gateway(e, mac),
+                        None => continue,
+                    };
+                    uplinks.send(ack.into()).await.unwrap();
+                }
+            }
+        });
+        mac
+    }
+
+    #[tokio::test]
+    async fn test_group_outcomes() {
+        let server = SocketAddr::from(([127, 0, 0, 1], 41690));
+        let mut runtime = UdpRuntime::new(server).await.unwrap();
+        let acking = gateway([1; 8], 41691, server, Some(Ok(()))).await;
+        let busy = gateway(
+            [2; 8],
+            41692,
+            server,
+            Some(Err(tx_ack::Error::CollisionPacket)),
+        )
+        .await;
+        let silent = gateway([3; 8], 41693, server, None).await;
+        let missing = MacAddress::new(&[4; 8]);
+        for _ in 0..3 {
+            runtime.recv().await;
+        }
+
+        let group = [acking, busy, silent, missing];
+        let outcomes = runtime
+            .send_to_group(txpk(), &group, Some(Duration::from_millis(500)))
+            .await;
+        let macs: Vec<MacAddress> = outcomes.iter().map(|(mac, _)| *mac).collect();
+        assert_eq!(macs, group);
+        assert!(outcomes[0].1.is_acked());
+        assert!(matches!(
+            outcomes[1].1,
+            Outcome::Rejected(tx_ack::Error::CollisionPacket)
+        ));
+        assert!(matches!(outcomes[2].1, Outcome::TimedOut));
+        assert!(matches!(outcomes[3].1, Outcome::NoClient));
+        // the token of the silent gateway was withdrawn with the others
+        assert!(runtime.tx.outstanding().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_timeout_cancels() {
+        let server = SocketAddr::from(([127, 0, 0, 1], 41696));
+        let mut runtime = UdpRuntime::new(server).await.unwrap();
+        let silent = gateway([6; 8], 41697, server, None).await;
+        runtime.recv().await;
+
+        assert!(matches!(
+            runtime
+                .send(txpk(), silent, Some(Duration::from_millis(100)))
+                .await,
+            Err(Error::SendTimeout)
+        ));
+        assert!(runtime.tx.outstanding().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let server = SocketAddr::from(([127, 0, 0, 1], 41694));
+        let mut runtime = UdpRuntime::new(server).await.unwrap();
+        let silent = gateway([5; 8], 41695, server, None).await;
+        runtime.recv().await;
+
+        let pending = runtime
+            .prepare_downlink(txpk(), silent)
+            .submit()
+            .await
+            .unwrap();
+        pending.cancel_handle().cancel().await.unwrap();
+        assert!(matches!(
+            pending.ack(Some(Duration::from_millis(500))).await,
+            Err(Error::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,319 @@
+/*
+   Optional enforcement of the regulatory limits of a region on the
+   downlinks of each gateway: the dwell time caps the time on air of any
+   single transmission, and the duty cycle of a sub-band caps the share of
+   time a gateway transmits in it over a sliding window.
+
+   Air time is charged to a gateway when its downlink is accepted rather
+   than when it goes on the air, at most seconds later, and refunded when
+   the gateway refuses it or is not connected. Sub-bands without a duty
+   cycle, like those of US915, are not tracked.
+*/
+use crate::{
+    pull_resp::{self, TxPk},
+    region::{Plan, SubBand},
+    MacAddress,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Clone)]
+pub struct LimiterConfig {
+    pub plan: Plan,
+    /// span over which duty cycles are measured
+    pub window: Duration,
+}
+
+impl LimiterConfig {
+    /// Measures duty cycles over an hour, as ETSI EN 300 220 does
+    pub fn new(plan: Plan) -> LimiterConfig {
+        LimiterConfig {
+            plan,
+            window: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LimitError {
+    #[error("{0} MHz is in no sub-band of the plan")]
+    FrequencyOutOfPlan(f64),
+    #[error("{time_on_air:?} on air exceeds the dwell time of {dwell_time:?}")]
+    DwellTimeExceeded {
+        time_on_air: Duration,
+        dwell_time: Duration,
+    },
+    #[error("{time_on_air:?} on air exceeds the {remaining:?} left to gateway {mac} on {low}-{high} MHz")]
+    DutyCycleExceeded {
+        mac: MacAddress,
+        low: f64,
+        high: f64,
+        time_on_air: Duration,
+        remaining: Duration,
+    },
+}
+
+/// Air time of a gateway in a duty cycled sub-band over the current window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub sub_band: SubBand,
+    pub used: Duration,
+    pub remaining: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Transmission {
+    token: u16,
+    accepted: Instant,
+    time_on_air: Duration,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: LimiterConfig,
+    // by gateway and index of the sub-band in the plan
+    transmissions: HashMap<(MacAddress, usize), VecDeque<Transmission>>,
+}
+
+impl Inner {
+    fn used(&mut self, key: (MacAddress, usize), now: Instant) -> Duration {
+        let window = self.config.window;
+        match self.transmissions.get_mut(&key) {
+            Some(transmissions) => {
+                while transmissions
+                    .front()
+                    .is_some_and(|oldest| now.duration_since(oldest.accepted) >= window)
+                {
+                    transmissions.pop_front();
+                }
+                transmissions.iter().map(|t| t.time_on_air).sum()
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    fn budget(&mut self, mac: MacAddress, index: usize, now: Instant) -> Option<Budget> {
+        let sub_band = self.config.plan.sub_bands()[index];
+        let allowed = self.config.window.mul_f64(sub_band.duty_cycle()?);
+        let used = self.used((mac, index), now);
+        Some(Budget {
+            sub_band,
+            used,
+            remaining: allowed.saturating_sub(used),
+        })
+    }
+
+    // index of the duty cycled sub-band to charge, if any
+    fn check(
+        &mut self,
+        mac: MacAddress,
+        txpk: &TxPk,
+        now: Instant,
+    ) -> Result<Option<usize>, LimitError> {
+        let plan = &self.config.plan;
+        let time_on_air = txpk.time_on_air();
+        if let Some(dwell_time) = plan.dwell_time() {
+            if time_on_air > dwell_time {
+                return Err(LimitError::DwellTimeExceeded {
+                    time_on_air,
+                    dwell_time,
+                });
+            }
+        }
+        let index = plan
+            .sub_bands()
+            .iter()
+            .position(|band| band.contains(txpk.freq))
+            .ok_or(LimitError::FrequencyOutOfPlan(txpk.freq))?;
+        match self.budget(mac, index, now) {
+            Some(budget) if time_on_air > budget.remaining => Err(LimitError::DutyCycleExceeded {
+                mac,
+                low: budget.sub_band.low(),
+                high: budget.sub_band.high(),
+                time_on_air,
+                remaining: budget.remaining,
+            }),
+            Some(_) => Ok(Some(index)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Air time of every gateway, shared by the runtime halves
+#[derive(Debug, Clone)]
+pub(crate) struct Limiter(Arc<Mutex<Inner>>);
+
+impl Limiter {
+    pub fn new(config: LimiterConfig) -> Limiter {
+        Limiter(Arc::new(Mutex::new(Inner {
+            config,
+            transmissions: HashMap::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // every update leaves the map consistent, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Drops the candidates which would exceed a limit, returning why the
+    /// first one was refused when none is left
+    pub fn filter(
+        &self,
+        candidates: &mut Vec<(pull_resp::Packet, MacAddress)>,
+    ) -> Result<(), LimitError> {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        let mut refused = None;
+        candidates.retain(
+            |(packet, mac)| match inner.check(*mac, &packet.data.txpk, now) {
+                Ok(_) => true,
+                Err(e) => {
+                    refused.get_or_insert(e);
+                    false
+                }
+            },
+        );
+        match refused {
+            Some(e) if candidates.is_empty() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Charges the air time of a downlink to its gateway
+    pub fn reserve(&self, mac: MacAddress, packet: &pull_resp::Packet) -> Result<(), LimitError> {
+        let mut inner = self.lock();
+        let txpk = &packet.data.txpk;
+        if let Some(index) = inner.check(mac, txpk, Instant::now())? {
+            inner
+                .transmissions
+                .entry((mac, index))
+                .or_default()
+                .push_back(Transmission {
+                    token: packet.random_token,
+                    accepted: Instant::now(),
+                    time_on_air: txpk.time_on_air(),
+                });
+        }
+        Ok(())
+    }
+
+    /// Refunds the air time of a downlink which never went on the air
+    pub fn release(&self, mac: &MacAddress, token: u16) {
+        let mut inner = self.lock();
+        for ((gateway, _), transmissions) in inner.transmissions.iter_mut() {
+            if gateway == mac {
+                transmissions.retain(|t| t.token != token);
+            }
+        }
+    }
+
+    /// Budget of the gateway in every duty cycled sub-band of the plan
+    pub fn budget(&self, mac: &MacAddress) -> Vec<Budget> {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        (0..inner.config.plan.sub_bands().len())
+            .filter_map(|index| inner.budget(*mac, index, now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{region::Region, CodingRate, Modulation, StringOrNum};
+
+    fn downlink(token: u16, freq: f64, datr: &str, size: usize) -> pull_resp::Packet {
+        let txpk = TxPk {
+            imme: true,
+            tmst: Some(StringOrNum::N(0)),
+            tmms: None,
+            freq,
+            rfch: 0,
+            powe: 14,
+            modu: Modulation::LORA,
+            datr: datr.parse().unwrap(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: size as u64,
+            data: vec![0; size],
+            ncrc: Some(true),
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        };
+        pull_resp::Packet {
+            random_token: token,
+            data: pull_resp::Data::from_txpk(txpk),
+        }
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        // 1% of 100 s leaves 1 s on air in 868.0-868.6 MHz
+        let limiter = Limiter::new(LimiterConfig {
+            window: Duration::from_secs(100),
+            ..LimiterConfig::new(Plan::new(Region::EU868))
+        });
+        let gateway = MacAddress::new(&[1; 8]);
+        let other = MacAddress::new(&[2; 8]);
+        let budget = limiter.budget(&gateway);
+        assert_eq!(budget.len(), 6);
+        assert!(budget.iter().all(|b| b.used == Duration::ZERO));
+
+        // 51 bytes at SF12 take about 2.5 s, 12 bytes at SF7 about 41 ms
+        let long = downlink(1, 868.1, "SF12BW125", 51);
+        assert!(matches!(
+            limiter.filter(&mut vec![(long.clone(), gateway)]),
+            Err(LimitError::DutyCycleExceeded { .. })
+        ));
+        for token in 0..24 {
+            limiter
+                .reserve(gateway, &downlink(token, 868.1, "SF7BW125", 12))
+                .unwrap();
+        }
+        let short = downlink(100, 868.3, "SF7BW125", 12);
+        assert!(limiter.reserve(gateway, &short).is_err());
+
+        // falls back on g3 and on the other gateway
+        let rx2 = downlink(101, 869.525, "SF12BW125", 51);
+        let mut candidates = vec![(short.clone(), gateway), (rx2, gateway), (short, other)];
+        assert_eq!(limiter.filter(&mut candidates), Ok(()));
+        assert_eq!(candidates.len(), 2);
+
+        limiter.release(&gateway, 0);
+        let sub_band = limiter.budget(&gateway)[2];
+        assert_eq!(sub_band.sub_band.low(), 868.0);
+        assert!(sub_band.remaining > Duration::from_millis(41));
+
+        // transmissions older than the window no longer count
+        let mut inner = limiter.lock();
+        let later = Instant::now() + Duration::from_secs(100);
+        assert_eq!(inner.used((gateway, 2), later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_dwell_time() {
+        let limiter = Limiter::new(LimiterConfig::new(Plan::new(Region::AS923)));
+        let gateway = MacAddress::new(&[1; 8]);
+        assert!(matches!(
+            limiter.reserve(gateway, &downlink(1, 923.2, "SF12BW125", 51)),
+            Err(LimitError::DwellTimeExceeded { .. })
+        ));
+        assert_eq!(
+            limiter.reserve(gateway, &downlink(2, 923.2, "SF7BW125", 51)),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.reserve(gateway, &downlink(3, 902.3, "SF7BW125", 12)),
+            Err(LimitError::FrequencyOutOfPlan(902.3))
+        );
+        // no duty cycle to report on
+        assert!(limiter.budget(&gateway).is_empty());
+    }
+}
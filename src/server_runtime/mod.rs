@@ -1,29 +1,83 @@
 use super::{
-    parser::Parser, pull_resp, pull_resp::TxPk, tx_ack::Packet as TxAck, MacAddress, Packet,
-    SerializablePacket, Up,
+    class_b::Beacon,
+    parser::Parser,
+    pull_resp,
+    pull_resp::TxPk,
+    time::{GpsTime, LeapSeconds},
+    tx_ack::{Error as TxAckError, Packet as TxAck},
+    MacAddress, Packet, SerializablePacket, UnparsedFrame, Up,
 };
 pub use crate::push_data::RxPk;
 use log::warn;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use std::{collections::HashMap, net::SocketAddr, time::Duration};
 use tokio::{
     net::UdpSocket,
     sync::{mpsc, oneshot},
-    time::timeout,
+    time::{sleep_until, timeout, timeout_at},
 };
 
+mod class_b;
+use class_b::Stats;
+pub use class_b::{ClassBConfig, PingSlots};
+
+mod clock;
+use clock::Clocks;
+
+mod group;
+pub use group::Outcome;
+
+mod retry;
+pub use retry::{Action, Attempt, Fallback, History, RetryPolicy};
+
+mod limiter;
+use limiter::Limiter;
+pub use limiter::{Budget, LimitError, LimiterConfig};
+
+mod scheduler;
+pub use clock::{ClockEstimate, ClockSync};
+pub use scheduler::{Priority, SchedulerConfig};
+use scheduler::{Scheduled, Scheduler};
+
 mod error;
 pub use error::Error;
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// When set, downlinks overlapping on the air of a gateway are moved
+    /// to their alternatives or rejected before being sent, and are sent
+    /// in the order they go on the air
+    pub scheduler: Option<SchedulerConfig>,
+    /// When set, downlinks which would exceed the dwell time or the duty
+    /// cycles of the plan are moved to their alternatives or rejected
+    pub limiter: Option<LimiterConfig>,
+    /// When set, beacons are emitted on every gateway with a GPS lock and
+    /// downlinks may be sent in ping slots
+    pub class_b: Option<ClassBConfig>,
+}
+
+// downlink with the alternatives to fall back on when it does not fit
+type Candidates = Vec<(pull_resp::Packet, MacAddress)>;
+// the ACK of the gateway, or why the downlink never reached it
+type AckSender = oneshot::Sender<Result<TxAck>>;
+// how long after cancelling a downlink its ACK is expected to show up
+const MAX_ACK_DELAY: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 enum InternalEvent {
-    Downlink((pull_resp::Packet, MacAddress, oneshot::Sender<TxAck>)),
+    Downlink((Candidates, Priority, AckSender)),
+    // tokens of every candidate of the downlink
+    Cancel(Vec<u16>),
     PacketBySocket((Packet, SocketAddr)),
     Client((MacAddress, SocketAddr)),
     PacketReceived(RxPk, MacAddress),
-    UnableToParseUdpFrame(Vec<u8>),
+    UnableToParseUdpFrame(UnparsedFrame),
     AckReceived(TxAck),
+    // tokens of the downlinks still queued or waiting for their ACK
+    #[cfg(all(test, feature = "client"))]
+    Outstanding(oneshot::Sender<Vec<u16>>),
 }
 
 #[derive(Debug, Clone)]
@@ -31,7 +85,7 @@ pub enum Event {
     PacketReceived(RxPk, MacAddress),
     NewClient((MacAddress, SocketAddr)),
     UpdateClient((MacAddress, SocketAddr)),
-    UnableToParseUdpFrame(Vec<u8>),
+    UnableToParseUdpFrame(UnparsedFrame),
     NoClientWithMac(Box<pull_resp::Packet>, MacAddress),
 }
 
@@ -41,7 +95,11 @@ pub enum Event {
 pub struct ClientTx {
     sender: mpsc::Sender<InternalEvent>,
     // you need to subscribe to the send channel
+    #[allow(dead_code)]
     receiver_copier: mpsc::Sender<Event>,
+    clocks: Clocks,
+    limiter: Option<Limiter>,
+    class_b: Option<ClassBConfig>,
 }
 
 // sends packets to clients
@@ -54,6 +112,8 @@ pub struct ClientRx {
 struct UdpRx {
     socket_receiver: Arc<UdpSocket>,
     internal_sender: mpsc::Sender<InternalEvent>,
+    clocks: Clocks,
+    stats: Stats,
 }
 
 // processes Internal Events and Transmit over UDP
@@ -61,14 +121,22 @@ struct Internal {
     receiver: mpsc::Receiver<InternalEvent>,
     client_tx_sender: mpsc::Sender<Event>,
     clients: HashMap<MacAddress, SocketAddr>,
-    downlink_senders: HashMap<u16, oneshot::Sender<TxAck>>,
+    downlink_senders: HashMap<u16, (MacAddress, AckSender)>,
     socket_sender: Arc<UdpSocket>,
+    clocks: Clocks,
+    scheduler: Option<Scheduler>,
+    limiter: Option<Limiter>,
+    // scheduled downlinks waiting to be sent, by due time
+    queue: Vec<(Instant, pull_resp::Packet, MacAddress, AckSender)>,
+    // downlinks cancelled after they were sent, whose ACK is ignored
+    cancelled: HashMap<u16, Instant>,
 }
 
 #[derive(Debug)]
 pub struct UdpRuntime {
     rx: ClientRx,
     tx: ClientTx,
+    local_addr: SocketAddr,
 }
 use rand::Rng;
 
@@ -76,45 +144,135 @@ use rand::Rng;
 pub struct Downlink {
     mac: MacAddress,
     packet: Option<pull_resp::Packet>,
+    alternatives: Candidates,
+    priority: Priority,
+    sender: mpsc::Sender<InternalEvent>,
+}
+
+/// Downlink handed over to the runtime, waiting for its ACK
+#[derive(Debug)]
+pub struct PendingDownlink {
+    receiver: oneshot::Receiver<Result<TxAck>>,
+    handle: CancelHandle,
+}
+
+/// Withdraws a pending downlink, from any task
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    tokens: Vec<u16>,
     sender: mpsc::Sender<InternalEvent>,
 }
 
+fn new_packet(txpk: TxPk) -> pull_resp::Packet {
+    pull_resp::Packet {
+        random_token: rand::thread_rng().gen(),
+        data: pull_resp::Data::from_txpk(txpk),
+    }
+}
+
 impl Downlink {
     pub fn set_packet(&mut self, txpk: TxPk) {
-        self.packet = Some(pull_resp::Packet {
-            random_token: rand::thread_rng().gen(),
-            data: pull_resp::Data::from_txpk(txpk),
-        });
+        self.packet = Some(new_packet(txpk));
+    }
+
+    /// With a scheduler or a limiter, the packet is sent on the first
+    /// alternative that fits when it would overlap another downlink or
+    /// exceed a limit, eg: RX2 or another gateway which heard the uplink.
+    /// Ignored without either.
+    pub fn add_alternative(&mut self, txpk: TxPk, mac: MacAddress) {
+        self.alternatives.push((new_packet(txpk), mac));
+    }
+
+    /// With a scheduler, the packet may pre-empt downlinks of a lower
+    /// priority which overlap it and were not sent to the gateway yet.
+    /// Ignored without a scheduler.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
     }
 
     pub fn get_destination_mac(&mut self) -> MacAddress {
         self.mac
     }
 
-    async fn just_dispatch(self) -> Result {
-        if let Some(packet) = self.packet {
-            let (sender, receiver) = oneshot::channel();
-
-            self.sender
-                .send(InternalEvent::Downlink((packet, self.mac, sender)))
-                .await?;
+    /// Hands the packet over to the runtime without waiting for its ACK,
+    /// so that it can still be cancelled
+    pub async fn submit(self) -> Result<PendingDownlink> {
+        let packet = self.packet.ok_or(Error::DispatchWithNoSendPacket)?;
+        let (sender, receiver) = oneshot::channel();
+        let mut candidates = vec![(packet, self.mac)];
+        candidates.extend(self.alternatives);
+        let tokens = candidates
+            .iter()
+            .map(|(packet, _)| packet.random_token)
+            .collect();
+
+        self.sender
+            .send(InternalEvent::Downlink((candidates, self.priority, sender)))
+            .await?;
+        Ok(PendingDownlink {
+            receiver,
+            handle: CancelHandle {
+                tokens,
+                sender: self.sender,
+            },
+        })
+    }
 
-            // wait for the ACK for the protocol layer
-            receiver.await?.get_result().map_err(|e| e.into())
-        } else {
-            Err(Error::DispatchWithNoSendPacket)
+    /// Sends the packet and waits for its ACK. The timeout covers the time
+    /// the packet spends queued until it is due as well; once it expires,
+    /// the packet is cancelled and the air time it reserved freed
+    pub async fn dispatch(self, timeout_duration: Option<Duration>) -> Result {
+        let duration = match timeout_duration {
+            Some(duration) => duration,
+            None => return self.submit().await?.receive().await,
+        };
+        let deadline = tokio::time::Instant::now() + duration;
+        let pending = timeout_at(deadline, self.submit()).await??;
+        let handle = pending.cancel_handle();
+        match timeout_at(deadline, pending.receive()).await {
+            Ok(result) => result,
+            Err(elapsed) => {
+                handle.cancel().await?;
+                Err(elapsed.into())
+            }
         }
     }
+}
 
-    pub async fn dispatch(self, timeout_duration: Option<Duration>) -> Result {
+impl PendingDownlink {
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.handle.clone()
+    }
+
+    pub async fn cancel(&self) -> Result {
+        self.handle.cancel().await
+    }
+
+    async fn receive(self) -> Result {
+        self.receiver.await??.get_result().map_err(|e| e.into())
+    }
+
+    /// Err(Error::Cancelled) once cancelled, even when the ACK comes later
+    pub async fn ack(self, timeout_duration: Option<Duration>) -> Result {
         if let Some(duration) = timeout_duration {
-            timeout(duration, self.just_dispatch()).await?
+            timeout(duration, self.receive()).await?
         } else {
-            self.just_dispatch().await
+            self.receive().await
         }
     }
 }
 
+impl CancelHandle {
+    /// Drops the downlink if it is still queued; when it already left for
+    /// the gateway it may go on the air, but its ACK is ignored
+    pub async fn cancel(&self) -> Result {
+        self.sender
+            .send(InternalEvent::Cancel(self.tokens.clone()))
+            .await?;
+        Ok(())
+    }
+}
+
 impl ClientRx {
     pub async fn recv(&mut self) -> Event {
         // we unwrap here because the send channel is dropped only iff ClientRx is dropped
@@ -129,19 +287,66 @@ impl ClientTx {
         prepared_send.dispatch(timeout).await
     }
 
-    pub fn prepare_downlink(&mut self, txpk: Option<TxPk>, mac: MacAddress) -> Downlink {
-        let packet = txpk.map(|txpk| pull_resp::Packet {
-            random_token: rand::thread_rng().gen(),
-            data: pull_resp::Data::from_txpk(txpk),
-        });
+    #[cfg(all(test, feature = "client"))]
+    pub(crate) async fn outstanding(&self) -> Vec<u16> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(InternalEvent::Outstanding(sender))
+            .await
+            .unwrap();
+        receiver.await.unwrap()
+    }
 
+    pub fn prepare_downlink(&mut self, txpk: Option<TxPk>, mac: MacAddress) -> Downlink {
         Downlink {
             mac,
-            packet,
+            packet: txpk.map(new_packet),
+            alternatives: Vec::new(),
+            priority: Priority::default(),
             sender: self.get_sender(),
         }
     }
 
+    /// Sends at a wall clock time: on GPS time when the gateway has a GPS
+    /// lock, else on its counter as estimated from its recent uplinks
+    pub async fn send_at(
+        &mut self,
+        txpk: TxPk,
+        mac: MacAddress,
+        at: SystemTime,
+        timeout: Option<Duration>,
+    ) -> Result {
+        let txpk = self.schedule_at(txpk, mac, at)?;
+        self.send(txpk, mac, timeout).await
+    }
+
+    /// Sets tmms or tmst of a packet to go out at a wall clock time
+    pub fn schedule_at(&self, mut txpk: TxPk, mac: MacAddress, at: SystemTime) -> Result<TxPk> {
+        let clock = self.clock_sync(&mac).ok_or(Error::NoClockSync(mac))?;
+        if clock.gps_locked() {
+            let tmms = GpsTime::from_utc(at, &LeapSeconds::default())
+                .ok_or(Error::UnschedulableTime(at))?;
+            txpk.set_gps_time(tmms);
+        } else {
+            let tmst = clock.tmst_at(at).ok_or(Error::UnschedulableTime(at))?;
+            txpk.set_timestamp(tmst);
+        }
+        Ok(txpk)
+    }
+
+    /// None until the gateway forwarded an uplink
+    pub fn clock_sync(&self, mac: &MacAddress) -> Option<ClockSync> {
+        self.clocks.get(mac)
+    }
+
+    /// Air time left to the gateway in each duty cycled sub-band, empty
+    /// without a limiter
+    pub fn budget(&self, mac: &MacAddress) -> Vec<Budget> {
+        self.limiter
+            .as_ref()
+            .map_or_else(Vec::new, |limiter| limiter.budget(mac))
+    }
+
     fn get_sender(&mut self) -> mpsc::Sender<InternalEvent> {
         self.sender.clone()
     }
@@ -152,6 +357,11 @@ impl UdpRuntime {
         (self.rx, self.tx)
     }
 
+    /// Address the gateways connect to, eg: the port picked when binding port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
     pub async fn send(&mut self, txpk: TxPk, mac: MacAddress, timeout: Option<Duration>) -> Result {
         self.tx.send(txpk, mac, timeout).await
     }
@@ -164,21 +374,64 @@ impl UdpRuntime {
         self.tx.prepare_downlink(Some(txpk), mac)
     }
 
+    pub async fn send_at(
+        &mut self,
+        txpk: TxPk,
+        mac: MacAddress,
+        at: SystemTime,
+        timeout: Option<Duration>,
+    ) -> Result {
+        self.tx.send_at(txpk, mac, at, timeout).await
+    }
+
+    pub fn clock_sync(&self, mac: &MacAddress) -> Option<ClockSync> {
+        self.tx.clock_sync(mac)
+    }
+
+    pub async fn send_ping_slot(
+        &mut self,
+        txpk: TxPk,
+        mac: MacAddress,
+        slots: &PingSlots,
+        timeout: Option<Duration>,
+    ) -> Result<GpsTime> {
+        self.tx.send_ping_slot(txpk, mac, slots, timeout).await
+    }
+
+    pub fn budget(&self, mac: &MacAddress) -> Vec<Budget> {
+        self.tx.budget(mac)
+    }
+
     pub async fn recv(&mut self) -> Event {
         self.rx.recv().await
     }
 
     pub async fn new(addr: SocketAddr) -> Result<UdpRuntime> {
+        Self::new_with_config(addr, Config::default()).await
+    }
+
+    pub async fn new_with_config(addr: SocketAddr, config: Config) -> Result<UdpRuntime> {
+        if let Some(class_b) = &config.class_b {
+            // fail early in regions without beacons
+            Beacon::at(GpsTime::from_millis(0)).txpk(&class_b.plan, class_b.tx_power)?;
+        }
         let socket = UdpSocket::bind(&addr).await?;
+        let local_addr = socket.local_addr()?;
         let socket_receiver = Arc::new(socket);
         let socket_sender = socket_receiver.clone();
 
         let (udp_tx_sender, udp_tx_receiver) = mpsc::channel(100);
         let (client_tx_sender, client_tx_receiver) = mpsc::channel(100);
 
+        let clocks = Clocks::default();
+        let limiter = config.limiter.map(Limiter::new);
+        let stats = Stats::default();
         let client_tx = ClientTx {
             sender: udp_tx_sender.clone(),
             receiver_copier: client_tx_sender.clone(),
+            clocks: clocks.clone(),
+            limiter: limiter.clone(),
+            class_b: config.class_b.clone(),
         };
 
         let client_rx = ClientRx {
@@ -188,6 +441,8 @@ impl UdpRuntime {
         let udp_rx = UdpRx {
             socket_receiver,
             internal_sender: udp_tx_sender,
+            clocks: clocks.clone(),
+            stats: stats.clone(),
         };
 
         let udp_tx = Internal {
@@ -196,8 +451,17 @@ impl UdpRuntime {
             clients: HashMap::new(),
             downlink_senders: HashMap::new(),
             socket_sender,
+            clocks,
+            scheduler: config.scheduler.map(Scheduler::new),
+            limiter,
+            queue: Vec::new(),
+            cancelled: HashMap::new(),
         };
 
+        if let Some(class_b) = config.class_b {
+            tokio::spawn(class_b::run_beacons(class_b, client_tx.clone(), stats));
+        }
+
         // udp_rx reads from the UDP port
         // and sends packets to relevant parties
         tokio::spawn(async move {
@@ -221,6 +485,7 @@ impl UdpRuntime {
         Ok(UdpRuntime {
             rx: client_rx,
             tx: client_tx,
+            local_addr,
         })
     }
 }
@@ -232,15 +497,16 @@ impl UdpRx {
             match self.socket_receiver.recv_from(&mut buf).await {
                 Err(e) => return Err(e.into()),
                 Ok((n, src)) => {
-                    let packet = if let Ok(packet) = Packet::parse(&buf[0..n]) {
-                        Some(packet)
-                    } else {
-                        let mut vec = Vec::new();
-                        vec.extend_from_slice(&buf[0..n]);
-                        self.internal_sender
-                            .send(InternalEvent::UnableToParseUdpFrame(vec))
-                            .await?;
-                        None
+                    let received = SystemTime::now();
+                    let packet = match Packet::parse(&buf[0..n]) {
+                        Ok(packet) => Some(packet),
+                        Err(e) => {
+                            let frame = UnparsedFrame::new(&buf[0..n], src, e);
+                            self.internal_sender
+                                .send(InternalEvent::UnableToParseUdpFrame(frame))
+                                .await?;
+                            None
+                        }
                     };
                     if let Some(packet) = packet {
                         match packet {
@@ -269,9 +535,17 @@ impl UdpRx {
                                             .await?;
                                     }
                                     Up::PushData(push_data) => {
+                                        if let Some(stat) = &push_data.data.stat {
+                                            self.stats.record(push_data.gateway_mac, stat);
+                                        }
                                         // Send all received packets as RxPk Events
                                         if let Some(rxpk) = &push_data.data.rxpk {
                                             for packet in rxpk {
+                                                self.clocks.record(
+                                                    push_data.gateway_mac,
+                                                    received,
+                                                    packet,
+                                                );
                                                 self.internal_sender
                                                     .send(InternalEvent::PacketReceived(
                                                         packet.clone(),
@@ -305,10 +579,97 @@ impl UdpRx {
 }
 
 impl Internal {
+    async fn send_downlink(
+        &mut self,
+        packet: pull_resp::Packet,
+        mac: MacAddress,
+        ack_sender: AckSender,
+        buf: &mut [u8],
+    ) -> Result {
+        if let Some(addr) = self.clients.get(&mac) {
+            let n = packet.serialize(buf)? as usize;
+            // We receive an error here if we are trying to send the packet to a
+            // client that is no longer connected to us. Delete the client from map
+            if self.socket_sender.send_to(&buf[..n], addr).await.is_err() {
+                warn!("Client {} not connected", mac);
+                self.clients.remove(&mac);
+            } else {
+                // store token and one-shot channel
+                self.downlink_senders
+                    .insert(packet.random_token, (mac, ack_sender));
+                return Ok(());
+            }
+        }
+        self.release(&mac, packet.random_token);
+        self.client_tx_sender
+            .send(Event::NoClientWithMac(packet.into(), mac))
+            .await?;
+        let _ = ack_sender.send(Err(Error::UnknownMac));
+        Ok(())
+    }
+
+    // answers a queued downlink with an error instead of sending it
+    fn withdraw(&mut self, token: u16, error: Error) -> bool {
+        match self
+            .queue
+            .iter()
+            .position(|(_, packet, ..)| packet.random_token == token)
+        {
+            Some(at) => {
+                let (_, _, mac, ack_sender) = self.queue.remove(at);
+                self.release(&mac, token);
+                let _ = ack_sender.send(Err(error));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn cancel(&mut self, token: u16) {
+        if self.withdraw(token, Error::Cancelled) {
+            return;
+        }
+        if let Some((_, ack_sender)) = self.downlink_senders.remove(&token) {
+            let _ = ack_sender.send(Err(Error::Cancelled));
+            self.cancelled
+                .retain(|_, cancelled| cancelled.elapsed() < MAX_ACK_DELAY);
+            self.cancelled.insert(token, Instant::now());
+        }
+    }
+
+    // frees the air time of a downlink which did not go on the air
+    fn release(&mut self, mac: &MacAddress, token: u16) {
+        if let Some(scheduler) = &mut self.scheduler {
+            scheduler.release(mac, token);
+        }
+        if let Some(limiter) = &self.limiter {
+            limiter.release(mac, token);
+        }
+    }
+
     pub async fn run(mut self) -> Result {
         let mut buf = vec![0u8; 1024];
         loop {
-            let msg = self.receiver.recv().await;
+            let msg = match self.queue.first() {
+                Some((due, ..)) => {
+                    let due = *due;
+                    tokio::select! {
+                        // a steady stream of events must not hold back due downlinks
+                        biased;
+                        _ = sleep_until(due.into()) => {
+                            let (_, packet, mac, ack_sender) = self.queue.remove(0);
+                            if let Some(scheduler) = &mut self.scheduler {
+                                scheduler.sent(&mac, packet.random_token);
+                            }
+                            self.send_downlink(packet, mac, ack_sender, &mut buf)
+                                .await?;
+                            continue;
+                        }
+                        msg = self.receiver.recv() => msg,
+                    }
+                }
+                None => self.receiver.recv().await,
+            };
             if let Some(msg) = msg {
                 match msg {
                     InternalEvent::UnableToParseUdpFrame(frame) => {
@@ -321,33 +682,83 @@ impl Internal {
                             .send(Event::PacketReceived(rxpk, mac))
                             .await?;
                     }
-                    InternalEvent::Downlink((packet, mac, ack_sender)) => {
-                        let mut no_client = true;
-
-                        if let Some(addr) = self.clients.get(&mac) {
-                            let n = packet.serialize(&mut buf)? as usize;
-                            // We receive an error here if we are trying to send the packet to a
-                            // client that is no longer connected to us. Delete the client from map
-                            if self.socket_sender.send_to(&buf[..n], addr).await.is_err() {
-                                warn!("Client {} not connected", mac);
-                                self.clients.remove(&mac);
-                            } else {
-                                // store token and one-shot channel
-                                self.downlink_senders
-                                    .insert(packet.random_token, ack_sender);
-                                no_client = false;
+                    InternalEvent::Cancel(tokens) => {
+                        for token in tokens {
+                            self.cancel(token);
+                        }
+                    }
+                    #[cfg(all(test, feature = "client"))]
+                    InternalEvent::Outstanding(sender) => {
+                        let queued = self.queue.iter().map(|(_, packet, ..)| packet.random_token);
+                        let _ = sender.send(
+                            queued
+                                .chain(self.downlink_senders.keys().copied())
+                                .collect(),
+                        );
+                    }
+                    InternalEvent::Downlink((mut candidates, priority, ack_sender)) => {
+                        if let Some(limiter) = &self.limiter {
+                            if let Err(e) = limiter.filter(&mut candidates) {
+                                let _ = ack_sender.send(Err(e.into()));
+                                continue;
                             }
                         }
-                        if no_client {
-                            self.client_tx_sender
-                                .send(Event::NoClientWithMac(packet.into(), mac))
-                                .await?;
+                        let scheduled = match &mut self.scheduler {
+                            Some(scheduler) => {
+                                scheduler.schedule(&candidates, priority, &self.clocks)
+                            }
+                            None => Some(Scheduled {
+                                index: 0,
+                                due: Instant::now(),
+                                preempted: Vec::new(),
+                            }),
+                        };
+                        match scheduled {
+                            Some(Scheduled {
+                                index,
+                                due,
+                                preempted,
+                            }) => {
+                                for token in preempted {
+                                    self.withdraw(token, Error::Preempted);
+                                }
+                                let (packet, mac) = candidates.swap_remove(index);
+                                if let Some(limiter) = &self.limiter {
+                                    // checked by filter above, with nothing sent in between
+                                    let _ = limiter.reserve(mac, &packet);
+                                }
+                                if self.scheduler.is_none() {
+                                    self.send_downlink(packet, mac, ack_sender, &mut buf)
+                                        .await?;
+                                    continue;
+                                }
+                                let at = self.queue.partition_point(|(queued, ..)| *queued <= due);
+                                self.queue.insert(at, (due, packet, mac, ack_sender));
+                            }
+                            None => {
+                                // rejected like the gateway would, without bothering it
+                                let (packet, mac) = candidates.swap_remove(0);
+                                let nack = packet.into_nack_with_error_for_gateway(
+                                    TxAckError::CollisionPacket,
+                                    mac,
+                                );
+                                let _ = ack_sender.send(Ok(nack));
+                            }
                         }
                     }
                     InternalEvent::AckReceived(txack) => {
-                        if let Some(sender) = self.downlink_senders.remove(&txack.random_token) {
-                            sender.send(txack).map_err(|_| Error::AckSend)?;
-                        } else {
+                        if let Some((mac, sender)) =
+                            self.downlink_senders.remove(&txack.random_token)
+                        {
+                            if txack.get_result().is_err() {
+                                self.release(&mac, txack.random_token);
+                            }
+                            // the client stops listening once its ack() timed out
+                            let token = txack.random_token;
+                            if sender.send(Ok(txack)).is_err() {
+                                warn!("ACK for token {} arrived after its timeout", token);
+                            }
+                        } else if self.cancelled.remove(&txack.random_token).is_none() {
                             warn!(
                                 "ACK received for unknown random_token {}",
                                 txack.random_token
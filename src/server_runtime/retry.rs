@@ -0,0 +1,295 @@
+/*
+   Retries of a downlink the gateway refused. A policy maps every TX_ACK
+   error to what to do next: send a modified packet, eg: in RX2 after
+   TOO_LATE in RX1 or at a lower power after TX_POWER, send the same
+   packet from another gateway, or give up. Errors which are not TX_ACK
+   errors, like timeouts, end the dispatch right away.
+
+   Alternatives added to the downlink are only offered on its first
+   attempt.
+*/
+use super::{new_packet, Downlink, Error, Result};
+use crate::{pull_resp::TxPk, tx_ack, MacAddress};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Sends this packet instead, from the same gateway
+    Retry(TxPk),
+    /// Sends the same packet from another gateway
+    Redirect(MacAddress),
+    GiveUp,
+}
+
+pub trait RetryPolicy {
+    /// Called after every refusal, with the attempts so far; the last one
+    /// is the refused one
+    fn on_error(&mut self, error: tx_ack::Error, attempts: &[Attempt]) -> Action;
+}
+
+impl<F: FnMut(tx_ack::Error, &[Attempt]) -> Action> RetryPolicy for F {
+    fn on_error(&mut self, error: tx_ack::Error, attempts: &[Attempt]) -> Action {
+        self(error, attempts)
+    }
+}
+
+#[derive(Debug)]
+pub struct Attempt {
+    pub txpk: TxPk,
+    pub mac: MacAddress,
+    pub result: Result,
+}
+
+/// Every attempt of a dispatch, in order
+#[derive(Debug)]
+pub struct History {
+    pub attempts: Vec<Attempt>,
+}
+
+impl History {
+    pub fn is_ok(&self) -> bool {
+        self.attempts
+            .last()
+            .is_some_and(|attempt| attempt.result.is_ok())
+    }
+
+    /// Result of the last attempt
+    pub fn into_result(mut self) -> Result {
+        self.attempts
+            .pop()
+            .map_or(Err(Error::DispatchWithNoSendPacket), |attempt| {
+                attempt.result
+            })
+    }
+}
+
+/// Retries in RX2 on TOO_LATE, at a lower power on TX_POWER, and from the
+/// next of its gateways on any other error
+#[derive(Debug, Clone)]
+pub struct Fallback {
+    rx2: Option<TxPk>,
+    gateways: VecDeque<MacAddress>,
+    /// dB taken off the TX power on every TX_POWER
+    pub power_step: u64,
+    pub min_power: u64,
+    /// including the first one
+    pub max_attempts: usize,
+}
+
+impl Default for Fallback {
+    fn default() -> Fallback {
+        Fallback {
+            rx2: None,
+            gateways: VecDeque::new(),
+            power_step: 2,
+            min_power: 0,
+            max_attempts: 4,
+        }
+    }
+}
+
+impl Fallback {
+    /// Packet to send when RX1 is too late, eg: `Windows::rx2`
+    pub fn with_rx2(self, rx2: TxPk) -> Fallback {
+        Fallback {
+            rx2: Some(rx2),
+            ..self
+        }
+    }
+
+    /// Gateways to try in turn, eg: the others which heard the uplink
+    pub fn with_gateways(self, gateways: Vec<MacAddress>) -> Fallback {
+        Fallback {
+            gateways: gateways.into(),
+            ..self
+        }
+    }
+}
+
+impl RetryPolicy for Fallback {
+    fn on_error(&mut self, error: tx_ack::Error, attempts: &[Attempt]) -> Action {
+        let last = match attempts.last() {
+            Some(last) if attempts.len() < self.max_attempts => &last.txpk,
+            _ => return Action::GiveUp,
+        };
+        match error {
+            tx_ack::Error::TooLate => self.rx2.take().map_or(Action::GiveUp, Action::Retry),
+            tx_ack::Error::InvalidTransmitPower => match last.powe.checked_sub(self.power_step) {
+                Some(powe) if powe >= self.min_power => Action::Retry(TxPk {
+                    powe,
+                    ..last.clone()
+                }),
+                _ => Action::GiveUp,
+            },
+            _ => self
+                .gateways
+                .pop_front()
+                .map_or(Action::GiveUp, Action::Redirect),
+        }
+    }
+}
+
+impl Downlink {
+    /// Dispatches the packet until it is acked, the policy gives up or an
+    /// attempt fails with something else than a TX_ACK error; the timeout
+    /// applies to each attempt
+    pub async fn dispatch_with_retry<P: RetryPolicy>(
+        self,
+        policy: &mut P,
+        timeout: Option<Duration>,
+    ) -> History {
+        let mut attempts: Vec<Attempt> = Vec::new();
+        let mut downlink = self;
+        while let Some(packet) = &downlink.packet {
+            let (txpk, mac) = (packet.data.txpk.clone(), downlink.mac);
+            let (priority, sender) = (downlink.priority, downlink.sender.clone());
+            let result = downlink.dispatch(timeout).await;
+            let error = match &result {
+                Err(Error::Ack(error)) => Some(*error),
+                _ => None,
+            };
+            attempts.push(Attempt {
+                txpk: txpk.clone(),
+                mac,
+                result,
+            });
+            let (txpk, mac) = match error.map(|error| policy.on_error(error, &attempts)) {
+                Some(Action::Retry(txpk)) => (txpk, mac),
+                Some(Action::Redirect(mac)) => (txpk, mac),
+                Some(Action::GiveUp) | None => break,
+            };
+            downlink = Downlink {
+                mac,
+                packet: Some(new_packet(txpk)),
+                alternatives: Vec::new(),
+                priority,
+                sender,
+            };
+        }
+        History { attempts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodingRate, Modulation, StringOrNum};
+
+    fn txpk(tmst: u32, freq: f64, powe: u64) -> TxPk {
+        TxPk {
+            imme: false,
+            tmst: Some(StringOrNum::N(tmst)),
+            tmms: None,
+            freq,
+            rfch: 0,
+            powe,
+            modu: Modulation::LORA,
+            datr: "SF7BW125".parse().unwrap(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: 2,
+            data: vec![1, 2],
+            ncrc: Some(true),
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    fn refused(txpk: &TxPk, mac: MacAddress, error: tx_ack::Error) -> Attempt {
+        Attempt {
+            txpk: txpk.clone(),
+            mac,
+            result: Err(error.into()),
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let (gateway, other) = (MacAddress::new(&[1; 8]), MacAddress::new(&[2; 8]));
+        let rx1 = txpk(1_000_000, 868.1, 16);
+        let rx2 = txpk(2_000_000, 869.525, 27);
+        let mut policy = Fallback::default()
+            .with_rx2(rx2.clone())
+            .with_gateways(vec![other]);
+        let mut attempts = vec![refused(&rx1, gateway, tx_ack::Error::TooLate)];
+
+        match policy.on_error(tx_ack::Error::TooLate, &attempts) {
+            Action::Retry(txpk) => assert_eq!(txpk.tmst, rx2.tmst),
+            action => panic!("{:?}", action),
+        }
+        attempts.push(refused(&rx2, gateway, tx_ack::Error::InvalidTransmitPower));
+        let lower = match policy.on_error(tx_ack::Error::InvalidTransmitPower, &attempts) {
+            Action::Retry(txpk) => txpk,
+            action => panic!("{:?}", action),
+        };
+        assert_eq!(lower.powe, 25);
+        attempts.push(refused(&lower, gateway, tx_ack::Error::CollisionPacket));
+        assert!(matches!(
+            policy.on_error(tx_ack::Error::CollisionPacket, &attempts),
+            Action::Redirect(mac) if mac == other
+        ));
+
+        // out of attempts
+        attempts.push(refused(&lower, other, tx_ack::Error::CollisionPacket));
+        assert!(matches!(
+            policy.on_error(tx_ack::Error::CollisionPacket, &attempts),
+            Action::GiveUp
+        ));
+        let history = History { attempts };
+        assert!(!history.is_ok());
+        assert!(matches!(
+            history.into_result(),
+            Err(Error::Ack(tx_ack::Error::CollisionPacket))
+        ));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_dispatch_with_retry() {
+        use super::super::UdpRuntime;
+        use crate::{client_runtime, Down, Packet};
+        use std::net::SocketAddr;
+
+        let server = SocketAddr::from(([127, 0, 0, 1], 41700));
+        let mut runtime = UdpRuntime::new(server).await.unwrap();
+        let mac = [1; 8];
+        let local = SocketAddr::from(([127, 0, 0, 1], 41701));
+        let gateway = client_runtime::UdpRuntime::new(mac, local, server)
+            .await
+            .unwrap();
+        let (mut downlinks, uplinks) = (gateway.subscribe(), gateway.publish_to());
+        gateway.run().await.unwrap();
+        let mac = MacAddress::new(&mac);
+        // RX1 is always too late
+        tokio::spawn(async move {
+            while let Ok(packet) = downlinks.recv().await {
+                if let Packet::Down(Down::PullResp(packet)) = packet {
+                    let ack = if packet.data.txpk.freq == 868.1 {
+                        packet.into_nack_with_error_for_gateway(tx_ack::Error::TooLate, mac)
+                    } else {
+                        packet.into_ack_for_gateway(mac)
+                    };
+                    uplinks.send(ack.into()).await.unwrap();
+                }
+            }
+        });
+        runtime.recv().await;
+
+        let rx2 = txpk(2_000_000, 869.525, 27);
+        let mut policy = Fallback::default().with_rx2(rx2);
+        let history = runtime
+            .prepare_downlink(txpk(1_000_000, 868.1, 16), mac)
+            .dispatch_with_retry(&mut policy, Some(Duration::from_secs(1)))
+            .await;
+        assert!(history.is_ok());
+        assert_eq!(history.attempts.len(), 2);
+        assert!(matches!(
+            history.attempts[0].result,
+            Err(Error::Ack(tx_ack::Error::TooLate))
+        ));
+        assert_eq!(history.attempts[1].txpk.freq, 869.525);
+    }
+}
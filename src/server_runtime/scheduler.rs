@@ -0,0 +1,289 @@
+/*
+   Optional scheduling of downlinks on the server side. lora_pkt_fwd only
+   reports two downlinks overlapping on the air of a gateway with a
+   COLLISION_PACKET TX_ACK, once both were sent to it. The scheduler keeps
+   the window of air time taken by every downlink sent on tmst, so that a
+   request overlapping one of them moves on to its next alternative, or is
+   rejected, before anything reaches the gateway.
+
+   A downlink may pre-empt the downlinks of a lower priority it overlaps,
+   as long as they were not sent to the gateway yet, so that join accepts
+   and MAC commands get through bulk Class C traffic.
+
+   Accepted downlinks are held back until `lead` before they go on the air,
+   so they reach each gateway in the order of their start. Holding them
+   needs the clock sync of the gateway to map tmst onto the wall clock;
+   downlinks for gateways without sync, and those sent immediately or on
+   GPS time, are sent right away.
+*/
+use super::clock::Clocks;
+use crate::{pull_resp, ConcentratorTimestamp, MacAddress};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+// windows older than this can no longer be told apart from new ones on tmst
+const MAX_WINDOW_AGE: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// kept free after every downlink, on top of its time on air
+    pub guard: Duration,
+    /// how long before going on the air downlinks are sent to the gateway
+    pub lead: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            // TX_START_DELAY and TX_MARGIN_DELAY of lora_pkt_fwd, rounded up
+            guard: Duration::from_millis(3),
+            lead: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Class of a downlink, which may pre-empt the downlinks of the classes
+/// below it on the air of a gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// eg: Class C multicast and FUOTA
+    Bulk,
+    #[default]
+    Normal,
+    /// eg: join accepts and MAC commands
+    High,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    token: u16,
+    start: ConcentratorTimestamp,
+    end: ConcentratorTimestamp,
+    added: Instant,
+    priority: Priority,
+    // sent to the gateway, so it can no longer be pre-empted
+    sent: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Scheduled {
+    /// of the candidate which fits
+    pub index: usize,
+    /// when to send it to the gateway
+    pub due: Instant,
+    /// tokens of the downlinks it pre-empted on its gateway
+    pub preempted: Vec<u16>,
+}
+
+impl Window {
+    fn overlaps(&self, other: &Window) -> bool {
+        self.start.is_before(other.end) && other.start.is_before(self.end)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    config: SchedulerConfig,
+    windows: HashMap<MacAddress, Vec<Window>>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Scheduler {
+        Scheduler {
+            config,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Picks the first candidate which fits on the air of its gateway, or
+    /// only overlaps pending downlinks of a lower priority; None when all
+    /// of them collide
+    pub fn schedule(
+        &mut self,
+        candidates: &[(pull_resp::Packet, MacAddress)],
+        priority: Priority,
+        clocks: &Clocks,
+    ) -> Option<Scheduled> {
+        self.prune(clocks);
+        for (index, (packet, mac)) in candidates.iter().enumerate() {
+            let txpk = &packet.data.txpk;
+            let start = match txpk.get_timestamp() {
+                Some(start) => start,
+                // sent immediately or on GPS time, nothing to check against
+                None => {
+                    return Some(Scheduled {
+                        index,
+                        due: Instant::now(),
+                        preempted: Vec::new(),
+                    })
+                }
+            };
+            let window = Window {
+                token: packet.random_token,
+                start,
+                end: start + txpk.time_on_air() + self.config.guard,
+                added: Instant::now(),
+                priority,
+                sent: false,
+            };
+            let windows = self.windows.entry(*mac).or_default();
+            let overlapping: Vec<&Window> = windows
+                .iter()
+                .filter(|taken| taken.overlaps(&window))
+                .collect();
+            if overlapping
+                .iter()
+                .any(|taken| taken.sent || taken.priority >= priority)
+            {
+                continue;
+            }
+            let preempted = overlapping.iter().map(|taken| taken.token).collect();
+            windows.retain(|taken| !taken.overlaps(&window));
+            windows.push(window);
+            return Some(Scheduled {
+                index,
+                due: self.due(mac, start, clocks),
+                preempted,
+            });
+        }
+        None
+    }
+
+    fn due(&self, mac: &MacAddress, start: ConcentratorTimestamp, clocks: &Clocks) -> Instant {
+        let now = Instant::now();
+        clocks
+            .get(mac)
+            .and_then(|clock| clock.time_at(start))
+            .and_then(|on_air| {
+                (on_air - self.config.lead)
+                    .duration_since(SystemTime::now())
+                    .ok()
+            })
+            .map_or(now, |wait| now + wait)
+    }
+
+    /// Once a downlink left for the gateway, it can no longer be pre-empted
+    pub fn sent(&mut self, mac: &MacAddress, token: u16) {
+        if let Some(windows) = self.windows.get_mut(mac) {
+            for window in windows.iter_mut().filter(|window| window.token == token) {
+                window.sent = true;
+            }
+        }
+    }
+
+    /// Frees the window of a downlink the gateway refused
+    pub fn release(&mut self, mac: &MacAddress, token: u16) {
+        if let Some(windows) = self.windows.get_mut(mac) {
+            windows.retain(|window| window.token != token);
+        }
+    }
+
+    fn prune(&mut self, clocks: &Clocks) {
+        for (mac, windows) in self.windows.iter_mut() {
+            let now = clocks
+                .get(mac)
+                .and_then(|clock| clock.tmst_at(SystemTime::now()));
+            windows.retain(|window| {
+                let ahead = match now {
+                    Some(now) => now.is_before(window.end),
+                    None => true,
+                };
+                window.added.elapsed() < MAX_WINDOW_AGE && ahead
+            });
+        }
+        self.windows.retain(|_, windows| !windows.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pull_resp::TxPk, CodingRate, Modulation, StringOrNum};
+
+    fn downlink(token: u16, mac: u8, tmst: u32) -> (pull_resp::Packet, MacAddress) {
+        let txpk = TxPk {
+            imme: false,
+            tmst: Some(StringOrNum::N(tmst)),
+            tmms: None,
+            freq: 869.525,
+            rfch: 0,
+            powe: 14,
+            modu: Modulation::LORA,
+            datr: "SF7BW125".parse().unwrap(),
+            codr: CodingRate::_4_5,
+            fdev: None,
+            ipol: true,
+            prea: None,
+            size: 12,
+            data: vec![0; 12],
+            ncrc: Some(true),
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        };
+        let packet = pull_resp::Packet {
+            random_token: token,
+            data: pull_resp::Data::from_txpk(txpk),
+        };
+        (packet, MacAddress::new(&[mac; 8]))
+    }
+
+    #[test]
+    fn test_collision_avoidance() {
+        let clocks = Clocks::default();
+        let mut scheduler = Scheduler::new(SchedulerConfig::default());
+        let normal = Priority::Normal;
+
+        // 12 bytes at SF7 take about 41 ms, windows wrap around the counter
+        let first = downlink(1, 1, u32::MAX - 10_000);
+        let gateway = first.1;
+        assert!(scheduler
+            .schedule(&[first], normal, &clocks)
+            .is_some_and(|scheduled| scheduled.index == 0));
+
+        // overlaps on the same gateway, falls back on the other one
+        let same_gateway = downlink(2, 1, 20_000);
+        let other_gateway = downlink(3, 2, 20_000);
+        assert!(scheduler
+            .schedule(
+                &[same_gateway.clone(), other_gateway.clone()],
+                normal,
+                &clocks
+            )
+            .is_some_and(|scheduled| scheduled.index == 1));
+        assert!(scheduler
+            .schedule(&[other_gateway], normal, &clocks)
+            .is_none());
+
+        // once the gateway refused the first downlink, its window is free
+        scheduler.release(&gateway, 1);
+        assert!(scheduler
+            .schedule(&[same_gateway], normal, &clocks)
+            .is_some());
+        assert!(scheduler
+            .schedule(&[downlink(4, 1, 100_000)], normal, &clocks)
+            .is_some());
+    }
+
+    #[test]
+    fn test_preemption() {
+        let clocks = Clocks::default();
+        let mut scheduler = Scheduler::new(SchedulerConfig::default());
+        for (token, tmst) in [(1, 0), (2, 100_000)] {
+            assert!(scheduler
+                .schedule(&[downlink(token, 1, tmst)], Priority::Bulk, &clocks)
+                .is_some());
+        }
+        scheduler.sent(&MacAddress::new(&[1; 8]), 1);
+
+        // the first bulk downlink already left for the gateway
+        assert!(scheduler
+            .schedule(&[downlink(3, 1, 20_000)], Priority::High, &clocks)
+            .is_none());
+        assert!(scheduler
+            .schedule(&[downlink(4, 1, 90_000)], Priority::High, &clocks)
+            .is_some_and(|scheduled| scheduled.preempted == vec![2]));
+        assert!(scheduler
+            .schedule(&[downlink(5, 1, 120_000)], Priority::High, &clocks)
+            .is_none());
+    }
+}
@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("client runtime error")]
+    ClientRuntime(#[from] crate::client_runtime::Error),
+    #[error("simulator configured with no frequencies or datarates")]
+    EmptyChannelPlan,
+    #[error("simulator configured with no gateways")]
+    NoGateways,
+}
@@ -0,0 +1,526 @@
+/*
+   This module emulates a fleet of packet forwarders. Every virtual gateway
+   runs on top of its own client_runtime::UdpRuntime, periodically sends
+   uplinks, lets the runtime report its stats and answers every downlink it
+   receives with a TX_ACK, which makes it possible to load test a server
+   against thousands of gateways from a single machine
+*/
+use crate::{
+    client_runtime::{self, Clock, GatewayProfile, Health},
+    pull_resp,
+    push_data::{self, RSig, RxPk, RxPkV1, RxPkV2, CRC},
+    time, Bandwidth, CodingRate, DataRate, Down, MacAddress, Modulation, Packet, SpreadingFactor,
+};
+use log::warn;
+use rand::Rng;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc::Sender};
+use tokio::time::sleep;
+
+mod error;
+pub use error::Error;
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Which flavour of rxpk JSON the virtual gateways emit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxPkFormat {
+    V1,
+    V2,
+    /// each uplink randomly picks V1 or V2
+    Mixed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// server the virtual gateways connect to
+    pub host: SocketAddr,
+    /// number of virtual gateways
+    pub gateways: usize,
+    /// EUI of the first gateway, the following ones are allocated sequentially
+    pub first_mac: u64,
+    /// mean time between two uplinks of a single gateway
+    pub uplink_interval: Duration,
+    /// time between two stat reports of a single gateway
+    pub stat_interval: Duration,
+    /// uplink frequencies in MHz, picked at random for every uplink
+    pub frequencies: Vec<f64>,
+    /// uplink datarates, picked at random for every uplink
+    pub datarates: Vec<DataRate>,
+    pub rxpk_format: RxPkFormat,
+    /// number of antennas reported in the rsig of V2 frames
+    pub antennas: usize,
+    /// smallest and largest FRMPayload length of the generated uplinks
+    pub payload_len: (usize, usize),
+    /// when set, downlinks are checked by an emulated JIT queue instead of
+    /// being blindly acked
+    pub jit: Option<GatewayProfile>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: SocketAddr::from(([127, 0, 0, 1], 1680)),
+            gateways: 1,
+            first_mac: 0x00_80_00_00_a0_00_00_00,
+            uplink_interval: Duration::from_secs(10),
+            stat_interval: Duration::from_secs(30),
+            frequencies: vec![903.9, 904.1, 904.3, 904.5, 904.7, 904.9, 905.1, 905.3],
+            datarates: [
+                SpreadingFactor::SF7,
+                SpreadingFactor::SF8,
+                SpreadingFactor::SF9,
+                SpreadingFactor::SF10,
+            ]
+            .iter()
+            .map(|sf| DataRate::new(sf.clone(), Bandwidth::BW125))
+            .collect(),
+            rxpk_format: RxPkFormat::V1,
+            antennas: 2,
+            payload_len: (0, 24),
+            jit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    DownlinkReceived(MacAddress, Box<pull_resp::Packet>),
+}
+
+/// Aggregated traffic counters, either for the whole simulation or a single gateway
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Report {
+    pub uplinks_sent: u64,
+    pub stats_sent: u64,
+    pub push_acks_received: u64,
+    pub pull_acks_received: u64,
+    /// when a JIT profile is configured, only the downlinks it accepted
+    pub downlinks_received: u64,
+    pub tx_acks_sent: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    uplinks_sent: AtomicU64,
+    push_acks_received: AtomicU64,
+    pull_acks_received: AtomicU64,
+    downlinks_received: AtomicU64,
+    tx_acks_sent: AtomicU64,
+}
+
+impl Counters {
+    fn report(&self) -> Report {
+        Report {
+            uplinks_sent: self.uplinks_sent.load(Ordering::Relaxed),
+            stats_sent: 0,
+            push_acks_received: self.push_acks_received.load(Ordering::Relaxed),
+            pull_acks_received: self.pull_acks_received.load(Ordering::Relaxed),
+            downlinks_received: self.downlinks_received.load(Ordering::Relaxed),
+            tx_acks_sent: self.tx_acks_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn increment(total: &AtomicU64, gateway: &AtomicU64) {
+    total.fetch_add(1, Ordering::Relaxed);
+    gateway.fetch_add(1, Ordering::Relaxed);
+}
+
+pub struct Simulator {
+    gateways: Vec<(MacAddress, Arc<Counters>, Health)>,
+    counters: Arc<Counters>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Simulator {
+    pub async fn new(config: Config) -> Result<Simulator> {
+        if config.gateways == 0 {
+            return Err(Error::NoGateways);
+        }
+        if config.frequencies.is_empty() || config.datarates.is_empty() {
+            return Err(Error::EmptyChannelPlan);
+        }
+
+        let config = Arc::new(config);
+        let counters = Arc::new(Counters::default());
+        let (events, _) = broadcast::channel(1024);
+        let mut gateways = Vec::with_capacity(config.gateways);
+
+        for i in 0..config.gateways {
+            let mac = MacAddress::new(&config.first_mac.wrapping_add(i as u64).to_be_bytes());
+            let gateway_counters = Arc::new(Counters::default());
+            let health = VirtualGateway {
+                mac,
+                config: config.clone(),
+                counters: counters.clone(),
+                gateway_counters: gateway_counters.clone(),
+                events: events.clone(),
+                clock: Clock::default(),
+                dev_addr: rand::random(),
+                fcnt: 0,
+            }
+            .spawn()
+            .await?;
+            gateways.push((mac, gateway_counters, health));
+        }
+
+        Ok(Simulator {
+            gateways,
+            counters,
+            events,
+        })
+    }
+
+    pub fn gateways(&self) -> Vec<MacAddress> {
+        self.gateways.iter().map(|(mac, ..)| *mac).collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn report(&self) -> Report {
+        let mut report = self.counters.report();
+        report.stats_sent = self
+            .gateways
+            .iter()
+            .map(|(_, _, health)| health.stats().stat_reports_sent)
+            .sum();
+        report
+    }
+
+    pub fn gateway_report(&self, mac: &MacAddress) -> Option<Report> {
+        self.gateways
+            .iter()
+            .find(|(gateway, ..)| gateway == mac)
+            .map(|(_, counters, health)| {
+                let mut report = counters.report();
+                report.stats_sent = health.stats().stat_reports_sent;
+                report
+            })
+    }
+}
+
+struct VirtualGateway {
+    mac: MacAddress,
+    config: Arc<Config>,
+    counters: Arc<Counters>,
+    gateway_counters: Arc<Counters>,
+    events: broadcast::Sender<Event>,
+    clock: Clock,
+    dev_addr: u32,
+    fcnt: u16,
+}
+
+impl VirtualGateway {
+    async fn spawn(mut self) -> Result<Health> {
+        let local = if self.config.host.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let mut mac = [0; 8];
+        mac.copy_from_slice(self.mac.bytes());
+        let runtime_config = client_runtime::Config {
+            jit: self.config.jit.clone(),
+            stat_interval: Some(self.config.stat_interval),
+            ..Default::default()
+        };
+        let udp_runtime = client_runtime::UdpRuntime::new_with_config(
+            mac,
+            local,
+            self.config.host,
+            runtime_config,
+        )
+        .await?;
+        let (receiver, sender) = (udp_runtime.subscribe(), udp_runtime.publish_to());
+        self.clock = udp_runtime.clock();
+        let health = udp_runtime.health();
+        udp_runtime.run().await?;
+
+        tokio::spawn(acker(
+            self.mac,
+            // the JIT queue of the runtime acks downlinks on our behalf
+            self.config.jit.is_none(),
+            receiver,
+            sender.clone(),
+            self.counters.clone(),
+            self.gateway_counters.clone(),
+            self.events.clone(),
+        ));
+        tokio::spawn(self.uplinker(sender));
+        Ok(health)
+    }
+
+    async fn uplinker(mut self, sender: Sender<Packet>) {
+        // spread the gateways evenly over the first interval
+        sleep(self.config.uplink_interval.mul_f64(rand::random())).await;
+        loop {
+            let packet = push_data::Packet::from_rxpk(self.rxpk());
+            if sender.send(packet.into()).await.is_err() {
+                warn!("{} uplink channel closed", self.mac);
+                return;
+            }
+            increment(
+                &self.counters.uplinks_sent,
+                &self.gateway_counters.uplinks_sent,
+            );
+            let jitter = rand::thread_rng().gen_range(0.5..1.5);
+            sleep(self.config.uplink_interval.mul_f64(jitter)).await;
+        }
+    }
+
+    // an unconfirmed data up frame with a random FRMPayload and MIC
+    fn frame(&mut self) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let (min, max) = self.config.payload_len;
+        let len = rng.gen_range(min..=max.max(min));
+
+        let mut frame = vec![0x40];
+        frame.extend_from_slice(&self.dev_addr.to_le_bytes());
+        frame.push(0x00);
+        frame.extend_from_slice(&self.fcnt.to_le_bytes());
+        frame.push(rng.gen_range(1..=223));
+        frame.extend((0..len).map(|_| rng.gen::<u8>()));
+        frame.extend_from_slice(&rng.gen::<[u8; 4]>());
+        self.fcnt = self.fcnt.wrapping_add(1);
+        frame
+    }
+
+    fn rxpk(&mut self) -> RxPk {
+        let data = self.frame();
+        let tmst = self.clock.tmst();
+        let mut rng = rand::thread_rng();
+        let config = &self.config;
+
+        let chan = rng.gen_range(0..config.frequencies.len());
+        let freq = config.frequencies[chan];
+        let datr = config.datarates[rng.gen_range(0..config.datarates.len())].clone();
+        let rfch = (chan * 2 / config.frequencies.len()) as u64;
+        let size = data.len() as u64;
+
+        let v2 = match config.rxpk_format {
+            RxPkFormat::V1 => false,
+            RxPkFormat::V2 => true,
+            RxPkFormat::Mixed => rng.gen(),
+        };
+
+        // signal strength of the strongest antenna, the SNR follows the RSSI
+        // down to the noise floor
+        let rssi = rng.gen_range(-125..=-40);
+        let signal = |rng: &mut rand::rngs::ThreadRng, offset: i32| {
+            let rssic = rssi - offset;
+            let lsnr = ((rssic + 120) as f32 / 4.0 + rng.gen_range(-2.0..2.0)).clamp(-20.0, 12.0);
+            let lsnr = (lsnr * 10.0).round() / 10.0;
+            let rssis = if lsnr < 0.0 {
+                rssic + lsnr.floor() as i32
+            } else {
+                rssic
+            };
+            (rssic, rssis, lsnr)
+        };
+
+        if v2 {
+            let rsig = (0..config.antennas.max(1))
+                .map(|ant| {
+                    let offset = if ant == 0 { 0 } else { rng.gen_range(0..8) };
+                    let (rssic, rssis, lsnr) = signal(&mut rng, offset);
+                    RSig {
+                        ant,
+                        chan: chan as u64,
+                        rssic,
+                        rssis: Some(rssis),
+                        lsnr,
+                        etime: None,
+                        foff: Some(rng.gen_range(-5000..5000)),
+                        ftstat: None,
+                        ftver: None,
+                        ftdelta: None,
+                        extra: serde_json::Map::new(),
+                    }
+                })
+                .collect();
+            RxPk::V2(RxPkV2 {
+                aesk: 0,
+                brd: 0,
+                codr: CodingRate::_4_5,
+                data,
+                datr,
+                freq,
+                jver: 2,
+                modu: "LORA".into(),
+                rsig,
+                size,
+                stat: CRC::OK,
+                tmst,
+                delayed: Some(false),
+                tmms: None,
+                time: Some(time::utc_compact(SystemTime::now())),
+                extra: serde_json::Map::new(),
+            })
+        } else {
+            let (rssi, rssis, lsnr) = signal(&mut rng, 0);
+            RxPk::V1(RxPkV1 {
+                chan: chan as u64,
+                codr: CodingRate::_4_5,
+                data,
+                datr,
+                freq,
+                lsnr,
+                modu: Modulation::LORA,
+                rfch,
+                rssi,
+                rssis: Some(rssis),
+                size,
+                stat: CRC::OK,
+                tmst,
+                extra: serde_json::Map::new(),
+            })
+        }
+    }
+}
+
+async fn acker(
+    mac: MacAddress,
+    ack: bool,
+    mut receiver: broadcast::Receiver<Packet>,
+    sender: Sender<Packet>,
+    counters: Arc<Counters>,
+    gateway_counters: Arc<Counters>,
+    events: broadcast::Sender<Event>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(Packet::Down(Down::PullResp(packet))) => {
+                increment(
+                    &counters.downlinks_received,
+                    &gateway_counters.downlinks_received,
+                );
+                if ack {
+                    let ack = packet.clone().into_ack_for_gateway(mac);
+                    if sender.send(ack.into()).await.is_err() {
+                        return;
+                    }
+                    increment(&counters.tx_acks_sent, &gateway_counters.tx_acks_sent);
+                }
+                // nobody listening for events is fine
+                let _ = events.send(Event::DownlinkReceived(mac, packet));
+            }
+            Ok(Packet::Down(Down::PushAck(_))) => increment(
+                &counters.push_acks_received,
+                &gateway_counters.push_acks_received,
+            ),
+            Ok(Packet::Down(Down::PullAck(_))) => increment(
+                &counters.pull_acks_received,
+                &gateway_counters.pull_acks_received,
+            ),
+            Ok(Packet::Up(_)) => (),
+            Err(RecvError::Lagged(n)) => warn!("{} dropped {} downlink frames", mac, n),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_frame_serializes() {
+        let mut gateway = VirtualGateway {
+            mac: MacAddress::new(&[0; 8]),
+            config: Arc::new(Config {
+                rxpk_format: RxPkFormat::Mixed,
+                ..Default::default()
+            }),
+            counters: Arc::new(Counters::default()),
+            gateway_counters: Arc::new(Counters::default()),
+            events: broadcast::channel(1).0,
+            clock: Clock::default(),
+            dev_addr: 0x2601_1234,
+            fcnt: 41,
+        };
+        for _ in 0..32 {
+            let rxpk = gateway.rxpk();
+            assert_eq!(rxpk.get_data().len() as u64, *get_size(&rxpk));
+            let json = serde_json::to_string(&rxpk).unwrap();
+            let parsed: RxPk = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.get_data(), rxpk.get_data());
+        }
+        assert_eq!(gateway.fcnt, 41 + 32);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_against_server_runtime() {
+        use crate::server_runtime::{self, UdpRuntime};
+
+        let mut server = UdpRuntime::new("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let simulator = Simulator::new(Config {
+            host: server.local_addr(),
+            gateways: 1,
+            uplink_interval: Duration::from_millis(50),
+            stat_interval: Duration::from_millis(20),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mac = simulator.gateways()[0];
+
+        // the gateway must have pulled before it can be sent a downlink
+        let (mut pulled, mut uplinks) = (false, 0);
+        while !pulled || uplinks < 3 {
+            match server.recv().await {
+                server_runtime::Event::NewClient((client, _)) => pulled |= client == mac,
+                server_runtime::Event::PacketReceived(_, gateway) => {
+                    assert_eq!(gateway, mac);
+                    uplinks += 1;
+                }
+                _ => (),
+            }
+        }
+        let txpk = pull_resp::TxPk {
+            imme: true,
+            tmst: None,
+            freq: 868.1,
+            rfch: 0,
+            powe: 14,
+            modu: Modulation::LORA,
+            datr: DataRate::default(),
+            codr: CodingRate::_4_5,
+            ipol: true,
+            size: 2,
+            data: vec![1, 2],
+            tmms: None,
+            fdev: None,
+            prea: None,
+            ncrc: None,
+            nhdr: None,
+            extra: serde_json::Map::new(),
+        };
+        server
+            .prepare_downlink(txpk, mac)
+            .dispatch(Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+
+        let report = simulator.report();
+        assert!(report.uplinks_sent >= uplinks);
+        assert_eq!(report.downlinks_received, 1);
+        assert_eq!(report.tx_acks_sent, 1);
+        assert!(report.stats_sent > 0);
+        assert_eq!(simulator.gateway_report(&mac).unwrap().tx_acks_sent, 1);
+    }
+
+    fn get_size(rxpk: &RxPk) -> &u64 {
+        match rxpk {
+            RxPk::V1(pk) => &pk.size,
+            RxPk::V2(pk) => &pk.size,
+        }
+    }
+}
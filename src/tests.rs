@@ -1,5 +1,7 @@
+#![allow(clippy::assertions_on_constants)]
 use super::packet::parser::Parser;
 use super::*;
+use std::time::{Duration, UNIX_EPOCH};
 #[test]
 fn test_pull_data() {
     let recv = [
@@ -71,6 +73,13 @@ fn test_push_data_rxpk_jsonv2() {
     let packet = Packet::parse(&recv).unwrap();
 
     if let Packet::Up(Up::PushData(packet)) = packet {
+        let rxpk = &packet.data.rxpk.as_ref().unwrap()[0];
+        assert_eq!(
+            rxpk.get_time(),
+            Some(UNIX_EPOCH + Duration::from_micros(1_603_987_060_170_301))
+        );
+        assert_eq!(rxpk.get_gps_time(), None);
+
         let mut buffer = [0; 512];
         let written = packet.serialize(&mut buffer).unwrap();
         let _packet = Packet::parse(&buffer[..written as usize]).unwrap();
@@ -95,6 +104,10 @@ fn test_push_data_stat() {
     let packet = Packet::parse(&recv).unwrap();
 
     if let Packet::Up(Up::PushData(packet)) = packet {
+        assert_eq!(
+            packet.data.stat.as_ref().unwrap().get_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_583_305_262))
+        );
         let _packet_first_read = Packet::parse(&recv).unwrap();
 
         let mut buffer_first = [0; 512];
@@ -120,11 +133,14 @@ fn test_immediate_send() {
     let json = "{\"codr\":\"4/5\",\"data\":\"IHLF2EA+n8BFY1vrCU1k/Vg=\",\"datr\":\"SF10BW125\",\"freq\":904.1,\"imme\":true,\"ipol\":false,\"modu\":\"LORA\",\"powe\":27,\"rfch\":0,\"size\":87,\"tmst\":\"immediate\"}";
 
     let txpk: TxPk = serde_json::from_str(json).unwrap();
-    if let StringOrNum::S(_) = txpk.tmst {
+    if let Some(StringOrNum::S(_)) = txpk.tmst {
         assert!(true);
     } else {
         assert!(false);
     }
+    // a string tmst is sent on as it came
+    let json = serde_json::to_string(&txpk).unwrap();
+    assert!(json.contains("\"tmst\":\"immediate\""));
 }
 #[test]
 fn test_timed_send() {
@@ -132,13 +148,33 @@ fn test_timed_send() {
     let json = "{\"codr\":\"4/5\",\"data\":\"IHLF2EA+n8BFY1vrCU1k/Vg=\",\"datr\":\"SF10BW500\",\"freq\":926.9000244140625,\"imme\":false,\"ipol\":true,\"modu\":\"LORA\",\"powe\":27,\"rfch\":0,\"size\":17,\"tmst\":727050748}";
 
     let txpk: TxPk = serde_json::from_str(json).unwrap();
-    if let StringOrNum::N(_) = txpk.tmst {
+    if let Some(StringOrNum::N(_)) = txpk.tmst {
         assert!(true);
     } else {
         assert!(false);
     }
 }
 
+#[test]
+fn test_gps_send() {
+    use crate::packet::{pull_resp::TxPk, time::GpsTime};
+    let json = "{\"codr\":\"4/5\",\"data\":\"IHLF2EA+n8BFY1vrCU1k/Vg=\",\"datr\":\"SF10BW500\",\"freq\":926.9000244140625,\"imme\":false,\"ipol\":true,\"modu\":\"LORA\",\"powe\":27,\"rfch\":0,\"size\":17,\"tmst\":727050748}";
+
+    let mut txpk: TxPk = serde_json::from_str(json).unwrap();
+    txpk.set_gps_time(GpsTime::from_millis(1_296_414_245_500));
+    // lora_pkt_fwd only looks at tmms when there is no tmst
+    let json = serde_json::to_string(&txpk).unwrap();
+    assert!(!json.contains("tmst"));
+    assert!(json.contains("\"tmms\":1296414245500"));
+
+    let txpk: TxPk = serde_json::from_str(&json).unwrap();
+    assert_eq!(txpk.get_timestamp(), None);
+    assert_eq!(
+        txpk.get_gps_time(),
+        Some(GpsTime::from_millis(1_296_414_245_500))
+    );
+}
+
 #[test]
 fn new_packet() {
     let recv = [
@@ -194,3 +230,136 @@ fn push_data_stat_null_ackr() {
     // the unwrap is enough for the test here
     let _packet = Packet::parse(&recv).unwrap();
 }
+
+#[test]
+fn unparsable_frames() {
+    let mut recv = vec![2, 63, 101, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+    recv.extend_from_slice(b"{\"stat\":{\n\"rxnb\":\"many\"}}");
+    let error = match Packet::parse(&recv) {
+        Err(ParseError::Json(error)) => error,
+        result => panic!("{:?}", result),
+    };
+    assert_eq!(error.path, "stat.rxnb");
+    assert!(error.message.contains("\"many\""));
+    assert_eq!((error.line, error.column), (2, 13));
+
+    let src = std::net::SocketAddr::from(([127, 0, 0, 1], 1680));
+    let frame = UnparsedFrame::new(&recv, src, error.into());
+    assert_eq!(
+        frame.gateway_mac,
+        Some(MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]))
+    );
+
+    // errors below an rxpk, which is read through a serde_json::Value
+    let rxpk_error = |rxpk: &str| {
+        let mut recv = vec![2, 63, 101, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        recv.extend_from_slice(format!("{{\"rxpk\":[{}]}}", rxpk).as_bytes());
+        match Packet::parse(&recv) {
+            Err(ParseError::Json(error)) => error,
+            result => panic!("{:?}", result),
+        }
+    };
+    let error = rxpk_error(
+        r#"{"chan":2,"codr":"4/5","data":"AAE=","datr":"SF7BW126","freq":868.5,"lsnr":9.5,
+            "modu":"LORA","rfch":1,"rssi":-40,"size":2,"stat":1,"tmst":1}"#,
+    );
+    assert_eq!(error.path, "rxpk[0].datr");
+    assert_eq!(error.message, "String with invalid Bandwidth");
+    let error = rxpk_error(
+        r#"{"aesk":0,"brd":0,"codr":"4/5","data":"AAE=","datr":"SF7BW125","freq":868.5,
+            "jver":2,"modu":"LORA","size":2,"stat":1,"tmst":1,
+            "rsig":[{"ant":0,"chan":2,"rssic":-40,"lsnr":9.5},
+                    {"ant":1,"chan":2,"rssic":"strong","lsnr":9.5}]}"#,
+    );
+    assert_eq!(error.path, "rxpk[0].rsig[1].rssic");
+    assert!(error.message.contains("\"strong\""));
+    // a missing field is reported on the rxpk itself
+    let error = rxpk_error(r#"{"jver":2}"#);
+    assert_eq!(error.path, "rxpk[0]");
+    assert!(error.message.starts_with("missing field"));
+
+    // truncated header, and a down frame which carries no MAC
+    assert!(matches!(
+        Packet::parse(&recv[..8]),
+        Err(ParseError::TooShort(8))
+    ));
+    assert_eq!(parser::up_gateway_mac(&recv[..8]), None);
+    assert_eq!(
+        parser::up_gateway_mac(&[2, 0, 0, 3, 1, 2, 3, 4, 5, 6, 7, 8]),
+        None
+    );
+}
+
+#[test]
+fn unknown_fields_round_trip() {
+    let json = serde_json::json!({
+        "rxpk": [
+            {"chan": 2, "codr": "4/5", "data": "AAE=", "datr": "SF7BW125", "freq": 868.5,
+             "lsnr": 9.5, "modu": "LORA", "rfch": 1, "rssi": -40, "size": 2, "stat": 1,
+             "tmst": 3512348611u32, "meta": {"network": "private"}},
+            {"aesk": 0, "brd": 0, "codr": "4/5", "data": "AAE=", "datr": "SF7BW125",
+             "freq": 868.5, "jver": 2, "modu": "LORA", "size": 2, "stat": 1, "tmst": 1,
+             "delayed": null, "tmms": null, "time": null, "board_tag": 7,
+             "rsig": [{"ant": 0, "chan": 2, "rssic": -40, "rssis": null, "lsnr": 9.5,
+                       "etime": null, "foff": null, "ftstat": null, "ftver": null,
+                       "ftdelta": null, "rssisd": 0}]}
+        ],
+        "stat": {"time": "2021-03-17 18:47:01 GMT", "rxnb": 1, "rxok": 1, "rxfw": 1,
+                 "ackr": 100.0, "dwnb": 0, "txnb": 0, "temp": 21},
+        "tags": ["rooftop"]
+    });
+    let data: push_data::Data = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(data.extra["tags"], serde_json::json!(["rooftop"]));
+    match &data.rxpk.as_ref().unwrap()[..] {
+        [push_data::RxPk::V1(v1), push_data::RxPk::V2(v2)] => {
+            assert_eq!(v1.extra["meta"]["network"], "private");
+            assert_eq!(v2.extra["board_tag"], 7);
+            assert_eq!(v2.rsig[0].extra["rssisd"], 0);
+        }
+        rxpk => panic!("{:?}", rxpk),
+    }
+    assert_eq!(serde_json::to_value(&data).unwrap(), json);
+
+    let json = serde_json::json!({"txpk": {"imme": true, "freq": 869.525, "rfch": 0,
+        "powe": 14, "modu": "LORA", "datr": "SF12BW125", "codr": "4/5", "ipol": true,
+        "prea": null, "size": 2, "data": "AAE=", "brd": 1}});
+    let data: pull_resp::Data = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(data.txpk.extra["brd"], 1);
+    assert_eq!(serde_json::to_value(&data).unwrap(), json);
+}
+
+#[test]
+fn multi_antenna_aggregation() {
+    let rxpk: push_data::RxPk = serde_json::from_str(
+        r#"{"jver":2,"brd":0,"aesk":0,"tmst":1,"freq":868.1,"modu":"LORA",
+            "datr":"SF12BW125","codr":"4/5","stat":1,"size":2,"data":"AAE=",
+            "rsig":[{"ant":0,"chan":1,"rssic":-118,"lsnr":-15.5},
+                    {"ant":1,"chan":5,"rssic":-121,"lsnr":-7.25},
+                    {"ant":2,"chan":1,"rssic":-115,"lsnr":-7.25}]}"#,
+    )
+    .unwrap();
+    // weak signals have negative SNRs, which used to all count as 0
+    assert_eq!(rxpk.get_snr(), -7.25);
+    let best = rxpk.best_reception().unwrap();
+    // ties are broken by RSSI
+    assert_eq!((best.ant, best.chan), (2, 1));
+    assert_eq!(rxpk.rssi(push_data::Aggregation::Best), Some(-115));
+    assert_eq!(rxpk.get_channel_rssi(), -115);
+
+    let combined = rxpk.snr(push_data::Aggregation::Combined).unwrap();
+    assert!((combined - -3.93).abs() < 0.01, "{}", combined);
+    assert_eq!(rxpk.rssi(push_data::Aggregation::Combined), Some(-113));
+    assert_eq!(rxpk.receptions().len(), 3);
+
+    // both RSSI come from the best reception, not the loudest antenna
+    let rxpk: push_data::RxPk = serde_json::from_str(
+        r#"{"jver":2,"brd":0,"aesk":0,"tmst":1,"freq":868.1,"modu":"LORA",
+            "datr":"SF12BW125","codr":"4/5","stat":1,"size":2,"data":"AAE=",
+            "rsig":[{"ant":0,"chan":1,"rssic":-90,"rssis":-92,"lsnr":-2.5},
+                    {"ant":1,"chan":1,"rssic":-100,"rssis":-101,"lsnr":6.0}]}"#,
+    )
+    .unwrap();
+    assert_eq!(rxpk.best_reception().unwrap().ant, 1);
+    assert_eq!(rxpk.get_channel_rssi(), -100);
+    assert_eq!(rxpk.get_signal_rssi(), Some(-101));
+}